@@ -0,0 +1,192 @@
+use log::{info, debug, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy, List};
+
+// What the server said identifies the version of a feed, sent back on the
+// next fetch so that an unchanged feed isn't downloaded again.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+// How long more calendars than there are workers may be waiting before it's
+// worth a warning.
+const QUEUE_WARN_AFTER: Duration = Duration::from_secs(30);
+
+pub enum Fetched {
+    Modified { ics: Vec<u8>, validators: Validators },
+    // 304, the version the validators belong to is still current.
+    NotModified,
+}
+
+impl Fetched {
+    pub fn is_not_modified(&self) -> bool {
+        match self {
+            Fetched::NotModified => true,
+            Fetched::Modified { .. } => false,
+        }
+    }
+}
+
+pub fn fetch(url: &str, validators: Option<&Validators>) -> Result<Fetched, curl::Error> {
+    let mut ics = Vec::new();
+    let mut easy = Easy::new();
+    let mut encoding = None;
+    let mut received = Validators::default();
+
+    easy.url(url)?;
+    easy.fail_on_error(true)?;
+    // An empty string advertises every encoding curl supports, and has it
+    // decode the response transparently.
+    easy.accept_encoding("")?;
+    if let Some(validators) = validators {
+        let mut headers = List::new();
+        if let Some(ref etag) = validators.etag {
+            headers.append(&format!("If-None-Match: {}", etag))?;
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            headers.append(&format!("If-Modified-Since: {}", last_modified))?;
+        }
+        easy.http_headers(headers)?;
+    }
+    {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|header| {
+            if let Ok(header) = std::str::from_utf8(header) {
+                let mut parts = header.splitn(2, ':');
+                if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    let name = name.trim();
+                    let value = Some(value.trim().to_string());
+                    if name.eq_ignore_ascii_case("Content-Encoding") {
+                        encoding = value;
+                    } else if name.eq_ignore_ascii_case("ETag") {
+                        received.etag = value;
+                    } else if name.eq_ignore_ascii_case("Last-Modified") {
+                        received.last_modified = value;
+                    }
+                }
+            }
+            true
+        })?;
+        transfer.write_function(|data| {
+            ics.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    if easy.response_code()? == 304 {
+        return Ok(Fetched::NotModified);
+    }
+    debug!("{}: Content-Encoding: {}", url, encoding.as_ref().map(|encoding| encoding.as_str()).unwrap_or("identity"));
+    Ok(Fetched::Modified { ics, validators: received })
+}
+
+// Watches the depth of the fetch queue, to warn once if it stays above a
+// threshold for longer than QUEUE_WARN_AFTER.
+struct QueueWatch {
+    threshold: usize,
+    high_since: Option<Instant>,
+    warned: bool,
+}
+
+impl QueueWatch {
+    fn new(threshold: usize) -> Self {
+        QueueWatch { threshold, high_since: None, warned: false }
+    }
+
+    // Whether to warn about `depth`, as seen at `now`.
+    fn check(&mut self, depth: usize, now: Instant) -> bool {
+        if depth <= self.threshold {
+            self.high_since = None;
+            return false;
+        }
+        let high_since = *self.high_since.get_or_insert(now);
+        if !self.warned && now.duration_since(high_since) >= QUEUE_WARN_AFTER {
+            self.warned = true;
+            return true;
+        }
+        false
+    }
+}
+
+// Fetches all URLs (each with the validators of the copy we have, if any), at
+// most `concurrency` at a time; the rest wait in a queue. The results are in
+// the same order as `feeds`.
+pub fn fetch_all(feeds: Vec<(String, Option<Validators>)>, concurrency: usize) -> Vec<(String, Result<Fetched, curl::Error>)> {
+    let count = feeds.len();
+    let workers = concurrency.max(1).min(count);
+    let queue = Arc::new(Mutex::new((feeds.into_iter().enumerate().collect::<VecDeque<_>>(), QueueWatch::new(workers))));
+    if count > workers {
+        info!("Fetching {} calendars, {} at a time.", count, workers);
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let handles = (0..workers).map(|_| {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            loop {
+                let job = {
+                    let mut guard = queue.lock().unwrap();
+                    let (queue, watch) = &mut *guard;
+                    let job = queue.pop_front();
+                    if watch.check(queue.len(), Instant::now()) {
+                        warn!("{} calendars still waiting for a fetch slot after {}s.", queue.len(), QUEUE_WARN_AFTER.as_secs());
+                    }
+                    job
+                };
+                match job {
+                    Some((index, (url, validators))) => {
+                        let result = fetch(&url, validators.as_ref());
+                        sender.send((index, url, result)).unwrap();
+                    },
+                    None => break,
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    drop(sender);
+
+    let mut results = receiver.iter().collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().expect("Fetch thread panicked");
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+    results.into_iter().map(|(_, url, result)| (url, result)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_once_when_the_queue_stays_deep() {
+        let start = Instant::now();
+        let mut watch = QueueWatch::new(2);
+        assert!(!watch.check(5, start));
+        assert!(!watch.check(4, start + QUEUE_WARN_AFTER / 2));
+        assert!(watch.check(3, start + QUEUE_WARN_AFTER));
+        assert!(!watch.check(3, start + QUEUE_WARN_AFTER * 2));
+    }
+
+    #[test]
+    fn shallow_queue_resets_the_timer() {
+        let start = Instant::now();
+        let mut watch = QueueWatch::new(2);
+        assert!(!watch.check(5, start));
+        assert!(!watch.check(2, start + QUEUE_WARN_AFTER / 2));
+        assert!(!watch.check(5, start + QUEUE_WARN_AFTER));
+        assert!(watch.check(5, start + QUEUE_WARN_AFTER * 2));
+    }
+}
@@ -1,15 +1,74 @@
-use log::{info, debug, error};
+use log::{info, debug, warn, error};
 use std::io::BufReader;
 use std::collections::HashMap;
 
-use chrono::{NaiveDateTime, Local, Duration};
+use chrono::{DateTime, NaiveDateTime, Local, Duration, Utc};
+use chrono_tz::Tz;
 use ical::parser::ical::component::IcalEvent;
-use curl::easy::Easy;
 use serde_json::json;
-use redis::{Commands, PipelineCommands};
+
+mod fetch;
+mod store;
+
+use fetch::Fetched;
+use store::{EventStore, RedisEventStore};
 
 const DEFAULT_URL: &'static str = "https://metalab.at/calendar/export/ical/";
-const EVENTS_KEY: &'static str = "events";
+// How many calendars are fetched at the same time.
+const CONCURRENCY_ENV: &'static str = "CALENDAR_CONCURRENCY";
+const DEFAULT_CONCURRENCY: usize = 4;
+// The space's time zone (an IANA name like "Europe/Vienna"), which the event
+// times are in. Defaults to the host's zone, which is often UTC on servers.
+const TIMEZONE_ENV: &'static str = "CALENDAR_TIMEZONE";
+// If set (to anything but an empty string or 0), overlapping and adjacent
+// events are also merged into the intervals in which anything takes place.
+const MERGE_ENV: &'static str = "CALENDAR_MERGE_INTERVALS";
+// At most this many events are stored per sync, the soonest ones, so that a
+// runaway feed can't flood Redis and the dashboard.
+const MAX_EVENTS_ENV: &'static str = "CALENDAR_MAX_EVENTS";
+const DEFAULT_MAX_EVENTS: usize = 1000;
+const DATETIME_FORMAT: &'static str = "%Y%m%dT%H%M%S";
+
+const REDIS_URL: &'static str = "redis://127.0.0.1/";
+
+const UTF8_BOM: &'static str = "\u{feff}";
+
+// Cleans up an ICS document before it's handed to the parser: invalid UTF-8
+// is replaced (with a warning), a leading byte order mark is removed, and
+// folded lines (RFC 5545 3.1, continuation lines starting with a space or a
+// tab) are joined.
+fn prepare_ics(url: &str, ics: &[u8]) -> String {
+    let text = String::from_utf8_lossy(ics);
+    if let std::borrow::Cow::Owned(_) = text {
+        warn!("{} is not valid UTF-8, replacing invalid sequences.", url);
+    }
+    let text = text.trim_start_matches(UTF8_BOM);
+    let mut unfolded = String::with_capacity(text.len());
+    for line in text.split('\n') {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            unfolded.push_str(&line[1..]);
+        } else {
+            if !unfolded.is_empty() {
+                unfolded.push('\n');
+            }
+            unfolded.push_str(line);
+        }
+    }
+    unfolded
+}
+
+// `now` in the given time zone (see TIMEZONE_ENV), or in the host's if
+// there's none.
+fn local_time(now: DateTime<Utc>, timezone: Option<&str>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) if !timezone.is_empty() => {
+            let timezone = timezone.parse::<Tz>().unwrap_or_else(|err| panic!("Invalid time zone {:?}: {}", timezone, err));
+            now.with_timezone(&timezone).naive_local()
+        },
+        _ => now.with_timezone(&Local).naive_local(),
+    }
+}
 
 fn process(event: &IcalEvent) -> Option<(String, String)> {
     let mut startstr = None;
@@ -36,64 +95,591 @@ fn process(event: &IcalEvent) -> Option<(String, String)> {
     }
 }
 
+fn property<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a str> {
+    event.properties.iter()
+        .find(|property| property.name == name)
+        .and_then(|property| property.value.as_ref().map(|value| value.as_str()))
+}
+
+// Cancelled events don't take place, so they aren't shown at all. Transparent
+// ones (TRANSP:TRANSPARENT, i.e. free time) are kept; their TRANSP property
+// ends up in the stored event like every other property.
+fn is_cancelled(event: &IcalEvent) -> bool {
+    property(event, "STATUS").map(|status| status.eq_ignore_ascii_case("CANCELLED")).unwrap_or(false)
+}
+
 fn event_to_hash_map(event: &IcalEvent) -> HashMap<String, String> {
     event.properties.iter().map(|property| (property.name.clone(), property.value.as_ref().and_then(|val| Some(val.clone())).unwrap_or(String::from("")))).collect()
 }
 
+// Keeps the events overlapping the window from `start` to `end`.
+fn events_in_window(events: Vec<IcalEvent>, start: NaiveDateTime, end: NaiveDateTime) -> Vec<HashMap<String, String>> {
+    events.into_iter().filter_map(|event| {
+        if is_cancelled(&event) {
+            debug!("Skipping cancelled event {:?}", property(&event, "SUMMARY"));
+            return None;
+        }
+        if let Some((startstr, endstr)) = process(&event) {
+            if let (Ok(event_start), Ok(event_end)) = (NaiveDateTime::parse_from_str(&startstr, DATETIME_FORMAT), NaiveDateTime::parse_from_str(&endstr, DATETIME_FORMAT)) {
+                if start < event_end && end > event_start {
+                    return Some(event_to_hash_map(&event));
+                }
+            }
+        }
+        None
+    }).collect()
+}
+
+// The first time after `start` at which the events kept for a window from
+// `start` to `end` would change if the window moved forward: an event in it
+// ends, or a later one starts to overlap it. None if that never happens.
+fn next_change(events: &[IcalEvent], start: NaiveDateTime, end: NaiveDateTime) -> Option<NaiveDateTime> {
+    events.iter().filter(|event| !is_cancelled(event)).filter_map(|event| {
+        let (startstr, endstr) = process(event)?;
+        let event_start = NaiveDateTime::parse_from_str(&startstr, DATETIME_FORMAT).ok()?;
+        let event_end = NaiveDateTime::parse_from_str(&endstr, DATETIME_FORMAT).ok()?;
+        if start < event_end && end > event_start {
+            Some(event_end)
+        } else if event_start >= end {
+            Some(event_start - end.signed_duration_since(start))
+        } else {
+            None
+        }
+    }).min()
+}
+
+// The events of a feed in the window from `start` to `end`, and when they
+// next change (see `next_change`). A feed may contain several VCALENDAR
+// blocks, all of them count.
+fn feed_events(url: &str, ics: &str, start: NaiveDateTime, end: NaiveDateTime) -> Result<(Vec<HashMap<String, String>>, Option<NaiveDateTime>), String> {
+    let reader = ical::IcalParser::new(BufReader::new(ics.as_bytes()));
+    let mut calendars = 0;
+    let mut total = 0;
+    let mut events = Vec::new();
+    let mut changes = Vec::new();
+    for cal in reader {
+        let cal = cal.map_err(|err| format!("Parse error in {}: {}", url, err))?;
+        calendars += 1;
+        total += cal.events.len();
+        changes.extend(next_change(&cal.events, start, end));
+        events.extend(events_in_window(cal.events, start, end));
+    }
+    if calendars == 0 {
+        return Err(format!("No calendar found in {}!", url));
+    }
+    info!("{}: {} calendar(s) with {} event(s)", url, calendars, total);
+    Ok((events, changes.into_iter().min()))
+}
+
+// Merges the times of the events (from all calendars) into the intervals in
+// which at least one of them takes place. Touching events are merged too.
+fn merge_intervals(events: &[HashMap<String, String>]) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut times = events.iter().filter_map(|event| {
+        let start = NaiveDateTime::parse_from_str(event.get("DTSTART")?, DATETIME_FORMAT).ok()?;
+        let end = NaiveDateTime::parse_from_str(event.get("DTEND")?, DATETIME_FORMAT).ok()?;
+        Some((start, end))
+    }).collect::<Vec<_>>();
+    times.sort();
+    let mut merged: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::new();
+    for (start, end) in times {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Keeps the `max` events that start first, returning how many were dropped.
+// The start times all have DATETIME_FORMAT (anything else was dropped in
+// `events_in_window`), which sorts like the times it stands for.
+fn limit_events(events: &mut Vec<HashMap<String, String>>, max: usize) -> usize {
+    if events.len() <= max {
+        return 0;
+    }
+    events.sort_by(|a, b| a.get("DTSTART").cmp(&b.get("DTSTART")));
+    let dropped = events.len() - max;
+    events.truncate(max);
+    dropped
+}
+
+fn store_intervals<S: EventStore>(store: &mut S, intervals: &[(NaiveDateTime, NaiveDateTime)]) -> redis::RedisResult<()> {
+    let intervals_json = intervals.iter().map(|(start, end)| json!({
+        "start": start.format(DATETIME_FORMAT).to_string(),
+        "end": end.format(DATETIME_FORMAT).to_string(),
+    }).to_string()).collect::<Vec<String>>();
+    store.replace_intervals(&intervals_json)
+}
+
+fn store_events<S: EventStore>(store: &mut S, events: &[HashMap<String, String>]) -> redis::RedisResult<()> {
+    let events_json = events.iter().filter_map(|event| serde_json::to_string(&json!(event)).ok()).collect::<Vec<String>>();
+    if events_json.len() == 0 {
+        info!("No events found.");
+    }
+    store.replace_events(&events_json)
+}
+
+// Fetches the feeds, giving up on the first one that fails.
+fn fetch_all(feeds: Vec<(String, Option<fetch::Validators>)>, concurrency: usize) -> Result<Vec<(String, Fetched)>, String> {
+    fetch::fetch_all(feeds, concurrency).into_iter()
+        .map(|(url, fetched)| match fetched {
+            Ok(fetched) => Ok((url, fetched)),
+            Err(err) => Err(format!("Failed fetching {}: {}", url, err)),
+        })
+        .collect()
+}
+
+// Everything a sync needs to know, from the command line and the environment.
+struct Settings {
+    urls: Vec<String>,
+    concurrency: usize,
+    max_events: usize,
+    merge: bool,
+    // The window of events to keep.
+    now: NaiveDateTime,
+    next_week: NaiveDateTime,
+}
+
+// The settings the stored events depend on, apart from the window.
+fn fingerprint(settings: &Settings) -> String {
+    json!({
+        "urls": settings.urls,
+        "max_events": settings.max_events,
+        "merge": settings.merge,
+    }).to_string()
+}
+
+// What the events stored by a sync with `settings` were computed from: the
+// settings, the start of the window and when the window would hold other
+// events (see `next_change`).
+fn describe_run(settings: &Settings, valid_until: Option<NaiveDateTime>) -> String {
+    json!({
+        "settings": fingerprint(settings),
+        "start": settings.now.format(DATETIME_FORMAT).to_string(),
+        "valid_until": valid_until.map(|valid_until| valid_until.format(DATETIME_FORMAT).to_string()),
+    }).to_string()
+}
+
+// Whether the events stored by `run` are the ones a sync with `settings`
+// would store, as long as no feed has changed.
+fn run_is_current(run: &str, settings: &Settings) -> bool {
+    let run = match serde_json::from_str::<serde_json::Value>(run) {
+        Ok(run) => run,
+        Err(_) => return false,
+    };
+    let time = |name: &str| run[name].as_str().and_then(|time| NaiveDateTime::parse_from_str(time, DATETIME_FORMAT).ok());
+    run["settings"].as_str() == Some(fingerprint(settings).as_str())
+        && time("start").map(|start| start <= settings.now).unwrap_or(false)
+        && (run["valid_until"].is_null() || time("valid_until").map(|valid_until| settings.now < valid_until).unwrap_or(false))
+}
+
+// Fetches the calendars and stores their events. If the stored events are
+// still the ones the current window and settings would hold, feeds fetched
+// before are fetched conditionally, and if none of them has changed, the
+// stored events are left as they are and only the sync time is updated.
+fn sync<S: EventStore>(store: &mut S, settings: Settings) -> Result<(), String> {
+    let current = match store.last_run() {
+        Ok(run) => run.map(|run| run_is_current(&run, &settings)).unwrap_or(false),
+        Err(err) => {
+            warn!("Failed reading the last run from redis: {}", err);
+            false
+        },
+    };
+    if !current {
+        info!("The stored events don't match the current window or settings, fetching every calendar in full.");
+    }
+    let feeds = settings.urls.iter().map(|url| {
+        let validators = match store.feed(url) {
+            Ok(validators) if current => validators,
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Failed reading the validators of {} from redis: {}", url, err);
+                None
+            },
+        };
+        (url.clone(), validators)
+    }).collect::<Vec<_>>();
+
+    let mut fetched = fetch_all(feeds, settings.concurrency)?;
+    if fetched.iter().all(|(_, fetched)| fetched.is_not_modified()) {
+        info!("No calendar has changed, keeping the stored events.");
+        return store.record_sync().map_err(|err| format!("Failed storing the sync time in redis: {}", err));
+    }
+    // The events aren't kept per feed, so the unchanged feeds are needed in
+    // full as well once any other one has changed.
+    let unchanged = fetched.iter()
+        .filter(|(_, fetched)| fetched.is_not_modified())
+        .map(|(url, _)| (url.clone(), None))
+        .collect::<Vec<_>>();
+    if !unchanged.is_empty() {
+        info!("Fetching {} unchanged calendar(s) again.", unchanged.len());
+        fetched.retain(|(_, fetched)| !fetched.is_not_modified());
+        fetched.extend(fetch_all(unchanged, settings.concurrency)?);
+    }
+
+    let mut events = Vec::new();
+    let mut changes = Vec::new();
+    let mut validators_to_store = Vec::new();
+    for (url, fetched) in fetched {
+        let (raw, validators) = match fetched {
+            Fetched::Modified { ics, validators } => (ics, validators),
+            Fetched::NotModified => return Err(format!("Got 304 for {} without sending validators", url)),
+        };
+        let ics = prepare_ics(&url, &raw);
+        let (feed, change) = feed_events(&url, &ics, settings.now, settings.next_week)?;
+        events.extend(feed);
+        changes.extend(change);
+        // Only kept if there's something to send back next time.
+        if !validators.is_empty() {
+            validators_to_store.push((url, validators));
+        }
+    }
+
+    let max_events = settings.max_events;
+    let dropped = limit_events(&mut events, max_events);
+    if dropped > 0 {
+        warn!("{} event(s) in the coming week, only storing the first {} ({} dropped).", events.len() + dropped, max_events, dropped);
+    }
+    store_events(store, &events).map_err(|err| format!("Failed storing events in redis: {}", err))?;
+    if settings.merge {
+        let intervals = merge_intervals(&events);
+        info!("{} event(s) merged into {} open interval(s)", events.len(), intervals.len());
+        store_intervals(store, &intervals).map_err(|err| format!("Failed storing open intervals in redis: {}", err))?;
+    }
+    // Only now that the events are stored, so that a failed sync isn't
+    // skipped as unchanged next time.
+    if let Err(err) = store.store_run(&describe_run(&settings, changes.into_iter().min())) {
+        warn!("Failed storing the last run in redis: {}", err);
+    }
+    for (url, validators) in validators_to_store {
+        if let Err(err) = store.store_feed(&url, &validators) {
+            warn!("Failed storing the validators of {} in redis: {}", url, err);
+        }
+    }
+    store.record_sync().map_err(|err| format!("Failed storing the sync time in redis: {}", err))
+}
+
 fn main() {
     env_logger::init();
 
-    info!("Fetching calendar...");
+    // The calendars to sync are given on the command line, falling back to
+    // the default one.
+    let mut urls = std::env::args().skip(1).collect::<Vec<String>>();
+    if urls.is_empty() {
+        urls.push(DEFAULT_URL.to_string());
+    }
+    let concurrency = std::env::var(CONCURRENCY_ENV).ok()
+        .map(|concurrency| concurrency.parse().expect("Invalid fetch concurrency."))
+        .unwrap_or(DEFAULT_CONCURRENCY);
+
+    info!("calendar {}", env!("CARGO_PKG_VERSION"));
+    info!("  calendars: {}", urls.join(", "));
+    let max_events = std::env::var(MAX_EVENTS_ENV).ok()
+        .map(|max_events| max_events.parse().expect("Invalid maximum number of events."))
+        .unwrap_or(DEFAULT_MAX_EVENTS);
+    info!("  concurrency: {}", concurrency);
+    info!("  max events: {}", max_events);
+    info!("  time zone: {}", std::env::var(TIMEZONE_ENV).unwrap_or_else(|_| String::from("local")));
+    info!("  redis: {}, key: {}", REDIS_URL, store::prefixed(store::EVENTS_KEY));
 
-    let now = Local::now().naive_local();
+    info!("Fetching {} calendar(s)...", urls.len());
+
+    let now = local_time(Utc::now(), std::env::var(TIMEZONE_ENV).ok().as_ref().map(|timezone| timezone.as_str()));
     let next_week = now.checked_add_signed(Duration::weeks(1)).unwrap();
+    let merge = std::env::var(MERGE_ENV).map(|merge| !merge.is_empty() && merge != "0").unwrap_or(false);
+
+    let mut store = RedisEventStore::connect(REDIS_URL).expect("Failed to connect to redis");
+    let settings = Settings { urls, concurrency, max_events, merge, now, next_week };
+    if let Err(message) = sync(&mut store, settings) {
+        // Also left in Redis, where the dashboard can show it. The next
+        // successful sync removes it again.
+        if let Err(err) = store.record_error(&message) {
+            warn!("Failed storing the error in redis: {}", err);
+        }
+        error!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use serde_json::json;
+    use crate::store::MemoryEventStore;
+
+    fn event(summary: &str, start: &str, end: &str, extra: &str) -> String {
+        format!("BEGIN:VEVENT\nSUMMARY:{}\nDTSTART:{}\nDTEND:{}\n{}END:VEVENT\n", summary, start, end, extra)
+    }
+
+    fn calendar(events: &[String]) -> String {
+        format!("BEGIN:VCALENDAR\nVERSION:2.0\nPRODID:-//cellardoor//tests//EN\n{}END:VCALENDAR\n", events.concat())
+    }
+
+    fn parse_events(ics: &str) -> Vec<IcalEvent> {
+        ical::IcalParser::new(BufReader::new(ics.as_bytes()))
+            .flat_map(|cal| cal.unwrap().events)
+            .collect()
+    }
+
+    fn time(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 6, day).and_hms(hour, 0, 0)
+    }
+
+    fn summaries(events: &[HashMap<String, String>]) -> Vec<&str> {
+        events.iter().map(|event| event["SUMMARY"].as_str()).collect()
+    }
+
+    #[test]
+    fn prepares_ics_for_the_parser() {
+        assert_eq!(prepare_ics("test", b"\xef\xbb\xbfBEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"), "BEGIN:VCALENDAR\nEND:VCALENDAR\n");
+        assert_eq!(prepare_ics("test", b"DESCRIPTION:a long\r\n  text,\r\n\tfolded\r\nEND:VEVENT"), "DESCRIPTION:a long text,folded\nEND:VEVENT");
+        assert_eq!(prepare_ics("test", b"SUMMARY:Caf\xe9\n"), "SUMMARY:Caf\u{fffd}\n");
+    }
+
+    #[test]
+    fn parses_folded_feeds_with_bom() {
+        let ics = format!("{}{}", UTF8_BOM, calendar(&[
+            event("meetup", "20190605T180000", "20190605T220000", "DESCRIPTION:first\n  second\n"),
+        ]).replace('\n', "\r\n"));
+        let events = parse_events(&prepare_ics("test", ics.as_bytes()));
+        assert_eq!(events.len(), 1);
+        assert_eq!(property(&events[0], "SUMMARY"), Some("meetup"));
+        assert_eq!(property(&events[0], "DESCRIPTION"), Some("first second"));
+    }
+
+    #[test]
+    fn uses_the_configured_time_zone() {
+        let now = Utc.ymd(2019, 6, 5).and_hms(10, 0, 0);
+        assert_eq!(local_time(now, Some("Europe/Vienna")), time(5, 12));
+        assert_eq!(local_time(now, Some("America/New_York")), time(5, 6));
+        assert_eq!(local_time(now, Some("UTC")), time(5, 10));
+        assert_eq!(local_time(now, Some("")), now.with_timezone(&Local).naive_local());
+        assert_eq!(local_time(now, None), now.with_timezone(&Local).naive_local());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid time zone")]
+    fn refuses_unknown_time_zones() {
+        local_time(Utc::now(), Some("Europe/Nowhere"));
+    }
+
+    #[test]
+    fn keeps_events_overlapping_the_window() {
+        let events = parse_events(&calendar(&[
+            event("before", "20190601T100000", "20190601T120000", ""),
+            event("overlapping start", "20190602T230000", "20190603T010000", ""),
+            event("inside", "20190605T180000", "20190605T220000", ""),
+            event("overlapping end", "20190609T230000", "20190610T010000", ""),
+            event("after", "20190610T000000", "20190610T020000", ""),
+            event("all day", "20190605", "20190606", ""),
+        ]));
+        let kept = events_in_window(events, time(3, 0), time(10, 0));
+        assert_eq!(summaries(&kept), vec!["overlapping start", "inside", "overlapping end"]);
+    }
+
+    #[test]
+    fn collects_events_from_every_calendar() {
+        let ics = format!("{}{}",
+            calendar(&[event("first", "20190605T180000", "20190605T220000", "")]),
+            calendar(&[
+                event("second", "20190606T180000", "20190606T220000", ""),
+                event("too late", "20190611T180000", "20190611T220000", ""),
+            ]),
+        );
+        let (events, change) = feed_events("test", &ics, time(3, 0), time(10, 0)).unwrap();
+        assert_eq!(summaries(&events), vec!["first", "second"]);
+        assert_eq!(change, Some(time(4, 18)));
+    }
 
-    let mut ics = Vec::new();
-    let mut easy = Easy::new();
-    let url: String = DEFAULT_URL.to_string();
-
-    easy.url(&url).expect("Invalid URL.");
-    easy.fail_on_error(true).unwrap();
-    {
-        let mut transfer = easy.transfer();
-        transfer.write_function(|data| {
-            ics.extend_from_slice(data);
-            Ok(data.len())
-        }).unwrap();
-        transfer.perform().unwrap();
-    }
-
-    let reader = ical::IcalParser::new(BufReader::new(&*ics));
-    let events = match reader.last() {
-        Some(Ok(cal)) => {
-            cal.events.into_iter().filter_map(|event| {
-                if let Some((startstr, endstr)) = process(&event) {
-                    if let (Ok(start), Ok(end)) = (NaiveDateTime::parse_from_str(&startstr, "%Y%m%dT%H%M%S"), NaiveDateTime::parse_from_str(&endstr, "%Y%m%dT%H%M%S")) {
-                        if now < end && next_week > start {
-                            return Some(event_to_hash_map(&event));
-                        }
+    #[test]
+    fn refuses_feeds_without_calendars() {
+        assert_eq!(feed_events("test", "", time(3, 0), time(10, 0)), Err(String::from("No calendar found in test!")));
+    }
+
+    #[test]
+    fn skips_cancelled_events() {
+        let events = parse_events(&calendar(&[
+            event("cancelled", "20190605T180000", "20190605T220000", "STATUS:CANCELLED\n"),
+            event("lowercase", "20190605T180000", "20190605T220000", "STATUS:cancelled\n"),
+            event("confirmed", "20190606T180000", "20190606T220000", "STATUS:CONFIRMED\n"),
+            event("free", "20190607T180000", "20190607T220000", "TRANSP:TRANSPARENT\n"),
+        ]));
+        assert!(is_cancelled(&events[0]));
+        assert!(is_cancelled(&events[1]));
+        assert!(!is_cancelled(&events[2]));
+        let kept = events_in_window(events, time(3, 0), time(10, 0));
+        assert_eq!(summaries(&kept), vec!["confirmed", "free"]);
+        assert_eq!(kept[1]["TRANSP"], "TRANSPARENT");
+    }
+
+    #[test]
+    fn stores_events_as_json() {
+        let events = events_in_window(parse_events(&calendar(&[
+            event("meetup", "20190605T180000", "20190605T220000", "LOCATION:Hauptraum\n"),
+        ])), time(3, 0), time(10, 0));
+        let mut store = MemoryEventStore::default();
+        store_events(&mut store, &events).unwrap();
+        assert_eq!(store.events.len(), 1);
+        let stored: HashMap<String, String> = serde_json::from_str(store.events.iter().next().unwrap()).unwrap();
+        assert_eq!(stored["SUMMARY"], "meetup");
+        assert_eq!(stored["LOCATION"], "Hauptraum");
+        assert_eq!(stored["DTSTART"], "20190605T180000");
+
+        store_events(&mut store, &[]).unwrap();
+        assert!(store.events.is_empty());
+    }
+
+    #[test]
+    fn merges_overlapping_and_touching_events() {
+        let events = events_in_window(parse_events(&calendar(&[
+            event("late", "20190606T200000", "20190606T230000", ""),
+            event("evening", "20190605T180000", "20190605T220000", ""),
+            event("overlapping", "20190605T210000", "20190605T230000", ""),
+            event("contained", "20190605T190000", "20190605T200000", ""),
+            event("touching", "20190605T230000", "20190606T010000", ""),
+            event("separate", "20190606T180000", "20190606T190000", ""),
+        ])), time(3, 0), time(10, 0));
+        assert_eq!(merge_intervals(&events), vec![
+            (time(5, 18), time(6, 1)),
+            (time(6, 18), time(6, 19)),
+            (time(6, 20), time(6, 23)),
+        ]);
+        assert!(merge_intervals(&[]).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_soonest_events() {
+        let mut events = events_in_window(parse_events(&calendar(&[
+            event("third", "20190607T180000", "20190607T220000", ""),
+            event("first", "20190605T180000", "20190605T220000", ""),
+            event("fourth", "20190608T180000", "20190608T220000", ""),
+            event("second", "20190606T180000", "20190606T220000", ""),
+        ])), time(3, 0), time(10, 0));
+        assert_eq!(limit_events(&mut events, 4), 0);
+        assert_eq!(events.len(), 4);
+        assert_eq!(limit_events(&mut events, 2), 2);
+        assert_eq!(summaries(&events), vec!["first", "second"]);
+        assert_eq!(limit_events(&mut events, 0), 2);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn stores_intervals_as_json() {
+        let mut store = MemoryEventStore::default();
+        store_intervals(&mut store, &[(time(5, 18), time(5, 22))]).unwrap();
+        let stored = store.intervals.iter().next().map(|interval| serde_json::from_str::<serde_json::Value>(interval).unwrap());
+        assert_eq!(stored, Some(json!({ "start": "20190605T180000", "end": "20190605T220000" })));
+    }
+
+    #[test]
+    fn successful_sync_clears_the_last_error() {
+        let mut store = MemoryEventStore::default();
+        store.record_error("Failed fetching test").unwrap();
+        assert_eq!(store.last_error, Some(String::from("Failed fetching test")));
+        store.record_sync().unwrap();
+        assert!(store.synced);
+        assert_eq!(store.last_error, None);
+    }
+
+    #[test]
+    fn finds_the_next_change_of_the_window() {
+        let events = parse_events(&calendar(&[
+            event("past", "20190601T100000", "20190601T120000", ""),
+            event("inside", "20190605T180000", "20190605T220000", ""),
+            event("cancelled", "20190603T180000", "20190603T220000", "STATUS:CANCELLED\n"),
+            event("next week", "20190611T180000", "20190611T220000", ""),
+        ]));
+        assert_eq!(next_change(&events, time(3, 0), time(10, 0)), Some(time(4, 18)));
+        assert_eq!(next_change(&events, time(5, 0), time(12, 0)), Some(time(5, 22)));
+        assert_eq!(next_change(&events, time(12, 0), time(19, 0)), None);
+    }
+
+    fn settings(urls: Vec<String>, now: NaiveDateTime) -> Settings {
+        Settings { urls, concurrency: 1, max_events: 10, merge: false, now, next_week: now + Duration::weeks(1) }
+    }
+
+    #[test]
+    fn stored_run_depends_on_the_window_and_settings() {
+        let run = describe_run(&settings(vec![String::from("a")], time(3, 0)), Some(time(4, 18)));
+        assert!(run_is_current(&run, &settings(vec![String::from("a")], time(3, 0))));
+        assert!(run_is_current(&run, &settings(vec![String::from("a")], time(4, 17))));
+        assert!(!run_is_current(&run, &settings(vec![String::from("a")], time(4, 18))));
+        assert!(!run_is_current(&run, &settings(vec![String::from("a")], time(2, 0))));
+        assert!(!run_is_current(&run, &settings(vec![String::from("b")], time(3, 0))));
+        let mut merged = settings(vec![String::from("a")], time(3, 0));
+        merged.merge = true;
+        assert!(!run_is_current(&run, &merged));
+        let forever = describe_run(&settings(vec![String::from("a")], time(3, 0)), None);
+        assert!(run_is_current(&forever, &settings(vec![String::from("a")], time(30, 0))));
+        assert!(!run_is_current("not json", &settings(vec![String::from("a")], time(3, 0))));
+    }
+
+    // Serves `ics` with an ETag, answering 304 to requests that send it
+    // back. Returns the URL and how many of those there were.
+    fn feed_server(ics: String) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/calendar.ics", listener.local_addr().unwrap().port());
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let counter = not_modified.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(n) if n > 0 => request.extend_from_slice(&buf[..n]),
+                        _ => break,
                     }
                 }
-                None
-            }).collect::<Vec<HashMap<String, String>>>()
-        },
-        Some(Err(err)) => {
-            panic!("Parse error: {}", err);
-        },
-        None => {
-            panic!("No calendar found!");
-        },
-    };
+                let response = if String::from_utf8_lossy(&request).contains("If-None-Match: \"v1\"") {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    String::from("HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n")
+                } else {
+                    format!("HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", ics.len(), ics)
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, not_modified)
+    }
 
-    let client = redis::Client::open("redis://127.0.0.1/").expect("Failed to set up redis client");
-    let con = client.get_connection().expect("Failed to connect to redis");
-    let events_json = events.iter().filter_map(|event| serde_json::to_string(&json!(event)).ok()).collect::<Vec<String>>();
-    if events_json.len() > 0 {
-        redis::transaction(&con, &[EVENTS_KEY], |pipe| {
-            pipe.del(EVENTS_KEY).sadd(EVENTS_KEY, events_json.clone()).query::<Option<()>>(&con)
-        }).expect("Failed executing redis transaction");
-    } else {
-        info!("No events found.");
-        con.del::<_, i32>(EVENTS_KEY).expect("Failed deleting events in redis");
+    fn stored_summaries(store: &MemoryEventStore) -> Vec<String> {
+        let mut summaries = store.events.iter()
+            .map(|event| serde_json::from_str::<HashMap<String, String>>(event).unwrap()["SUMMARY"].clone())
+            .collect::<Vec<_>>();
+        summaries.sort();
+        summaries
+    }
+
+    #[test]
+    fn unchanged_feeds_are_filtered_for_the_current_window() {
+        let (url, not_modified) = feed_server(calendar(&[
+            event("first", "20190605T180000", "20190605T220000", ""),
+            event("second", "20190611T180000", "20190611T220000", ""),
+        ]));
+        let mut store = MemoryEventStore::default();
+        sync(&mut store, settings(vec![url.clone()], time(4, 0))).unwrap();
+        assert_eq!(stored_summaries(&store), vec!["first"]);
+
+        // Nothing has left or entered the window yet, so the 304 keeps the
+        // stored events.
+        store.events.clear();
+        sync(&mut store, settings(vec![url.clone()], time(4, 12))).unwrap();
+        assert_eq!(not_modified.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(store.events.is_empty());
+
+        // By now "first" has ended and "second" is in the window, so the feed
+        // is fetched and filtered again although it's still the same.
+        sync(&mut store, settings(vec![url.clone()], time(6, 0))).unwrap();
+        assert_eq!(not_modified.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(stored_summaries(&store), vec!["second"]);
+        assert!(store.synced);
     }
 }
@@ -0,0 +1,223 @@
+use log::warn;
+use redis::{Commands, PipelineCommands, RedisResult};
+pub use rediskeys::prefixed;
+use crate::fetch::Validators;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Where the synced events end up. The real implementation writes them to
+// Redis; the in-memory one lets the sync logic run without a Redis server.
+
+pub const EVENTS_KEY: &'static str = "events";
+// The merged intervals in which any event takes place.
+pub const INTERVALS_KEY: &'static str = "open_intervals";
+// Hash from sync job to the Unix time of its last successful run.
+pub const LAST_SYNC_KEY: &'static str = "last_sync";
+// Hash from sync job to its last failure as JSON ({"time": ..., "message":
+// ...}), removed again by the next successful run.
+pub const LAST_ERROR_KEY: &'static str = "last_error";
+const JOB_NAME: &'static str = "calendar";
+// Hash per feed (`calendar_feed:<url>`) with the validators of the last
+// downloaded version, so that unchanged feeds can be fetched conditionally.
+const FEED_KEY_PREFIX: &'static str = "calendar_feed:";
+// What the stored events were computed from (as JSON, see `describe_run` in
+// main.rs), so that a sync in which no feed has changed can tell whether they
+// still hold.
+const RUN_KEY: &'static str = "calendar_run";
+
+// How often a transaction is attempted if the connection drops, e.g. because
+// Redis is restarted during the sync, and how long to wait before the first
+// retry (doubled for each further one).
+const TRANSACTION_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+pub trait EventStore {
+    // Replaces the complete set of stored events with `events`.
+    fn replace_events(&mut self, events: &[String]) -> RedisResult<()>;
+
+    // Replaces the stored open intervals with `intervals`.
+    fn replace_intervals(&mut self, intervals: &[String]) -> RedisResult<()>;
+    // Notes the current time as the time of the last sync, and forgets the
+    // last error.
+    fn record_sync(&mut self) -> RedisResult<()>;
+    // Notes why the sync failed.
+    fn record_error(&mut self, message: &str) -> RedisResult<()>;
+
+    // The validators of the last downloaded version of the feed at `url`.
+    fn feed(&mut self, url: &str) -> RedisResult<Option<Validators>>;
+    fn store_feed(&mut self, url: &str, validators: &Validators) -> RedisResult<()>;
+
+    // The description of the run the stored events came from.
+    fn last_run(&mut self) -> RedisResult<Option<String>>;
+    fn store_run(&mut self, run: &str) -> RedisResult<()>;
+}
+
+pub struct RedisEventStore {
+    client: redis::Client,
+    con: redis::Connection,
+    key: String,
+    intervals_key: String,
+}
+
+impl RedisEventStore {
+    pub fn connect(url: &str) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisEventStore {
+            con: client.get_connection()?,
+            client,
+            key: prefixed(EVENTS_KEY),
+            intervals_key: prefixed(INTERVALS_KEY),
+        })
+    }
+
+    // Runs `transaction` (or any other write), reconnecting and trying again
+    // if the connection fails. Errors reported by Redis itself aren't
+    // retried.
+    fn retry_transaction<T, F: FnMut(&redis::Connection) -> RedisResult<T>>(&mut self, mut transaction: F) -> RedisResult<T> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match transaction(&self.con) {
+                Err(ref err) if attempt < TRANSACTION_ATTEMPTS && (err.is_io_error() || err.is_connection_dropped()) => {
+                    warn!("Redis connection failed ({}), retrying in {}s.", err, backoff.as_secs());
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                    match self.client.get_connection() {
+                        Ok(con) => self.con = con,
+                        Err(err) => warn!("Reconnecting to redis failed: {}", err),
+                    }
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+impl EventStore for RedisEventStore {
+    fn replace_events(&mut self, events: &[String]) -> RedisResult<()> {
+        let key = self.key.clone();
+        self.replace_set(&key, events)
+    }
+
+    fn replace_intervals(&mut self, intervals: &[String]) -> RedisResult<()> {
+        let key = self.intervals_key.clone();
+        self.replace_set(&key, intervals)
+    }
+
+    fn record_sync(&mut self) -> RedisResult<()> {
+        let now = unix_time();
+        self.retry_transaction(|con| {
+            redis::pipe()
+                .hset(prefixed(LAST_SYNC_KEY), JOB_NAME, now).ignore()
+                .hdel(prefixed(LAST_ERROR_KEY), JOB_NAME).ignore()
+                .query(con)
+        })
+    }
+
+    fn record_error(&mut self, message: &str) -> RedisResult<()> {
+        let error = serde_json::json!({ "time": unix_time(), "message": message }).to_string();
+        self.retry_transaction(|con| con.hset(prefixed(LAST_ERROR_KEY), JOB_NAME, error.as_str()))
+    }
+
+    fn feed(&mut self, url: &str) -> RedisResult<Option<Validators>> {
+        let (etag, last_modified): (Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(prefixed(&format!("{}{}", FEED_KEY_PREFIX, url)))
+            .arg("etag").arg("last_modified")
+            .query(&self.con)?;
+        let validators = Validators { etag, last_modified };
+        Ok(Some(validators).filter(|validators| !validators.is_empty()))
+    }
+
+    fn store_feed(&mut self, url: &str, validators: &Validators) -> RedisResult<()> {
+        let key = prefixed(&format!("{}{}", FEED_KEY_PREFIX, url));
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if let Some(ref etag) = validators.etag {
+            pipe.hset(&key, "etag", etag).ignore();
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            pipe.hset(&key, "last_modified", last_modified).ignore();
+        }
+        self.retry_transaction(|con| pipe.query(con))
+    }
+
+    fn last_run(&mut self) -> RedisResult<Option<String>> {
+        self.con.get(prefixed(RUN_KEY))
+    }
+
+    fn store_run(&mut self, run: &str) -> RedisResult<()> {
+        self.retry_transaction(|con| con.set(prefixed(RUN_KEY), run))
+    }
+}
+
+impl RedisEventStore {
+    fn replace_set(&mut self, key: &str, members: &[String]) -> RedisResult<()> {
+        self.retry_transaction(|con| {
+            if members.len() > 0 {
+                redis::transaction(con, &[key], |pipe| {
+                    pipe.del(key).sadd(key, members).query::<Option<()>>(con)
+                })
+            } else {
+                con.del::<_, i32>(key).map(|_| ())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryEventStore {
+    pub events: std::collections::HashSet<String>,
+    pub intervals: std::collections::HashSet<String>,
+    pub synced: bool,
+    pub last_error: Option<String>,
+    pub feeds: std::collections::HashMap<String, Validators>,
+    pub run: Option<String>,
+}
+
+#[cfg(test)]
+impl EventStore for MemoryEventStore {
+    fn replace_events(&mut self, events: &[String]) -> RedisResult<()> {
+        self.events = events.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn replace_intervals(&mut self, intervals: &[String]) -> RedisResult<()> {
+        self.intervals = intervals.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn record_sync(&mut self) -> RedisResult<()> {
+        self.synced = true;
+        self.last_error = None;
+        Ok(())
+    }
+
+    fn record_error(&mut self, message: &str) -> RedisResult<()> {
+        self.last_error = Some(message.to_string());
+        Ok(())
+    }
+
+    fn feed(&mut self, url: &str) -> RedisResult<Option<Validators>> {
+        Ok(self.feeds.get(url).cloned())
+    }
+
+    fn store_feed(&mut self, url: &str, validators: &Validators) -> RedisResult<()> {
+        self.feeds.insert(url.to_string(), validators.clone());
+        Ok(())
+    }
+
+    fn last_run(&mut self) -> RedisResult<Option<String>> {
+        Ok(self.run.clone())
+    }
+
+    fn store_run(&mut self, run: &str) -> RedisResult<()> {
+        self.run = Some(run.to_string());
+        Ok(())
+    }
+}
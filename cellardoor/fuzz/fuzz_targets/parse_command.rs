@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+
+// cellardoor is a binary, so the protocol module is pulled in directly.
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+// Frames reach the parser straight from the network, before anything about
+// the client is known. Whatever comes in, parsing must end in a command or a
+// ParseError, never a panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let validation = protocol::validate(text);
+        assert_eq!(validation.ok, validation.command.is_some());
+        assert_eq!(validation.ok, validation.error.is_none());
+        if let Some(error) = validation.error {
+            let _ = error.to_string();
+        }
+    }
+});
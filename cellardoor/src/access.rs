@@ -0,0 +1,148 @@
+use {
+    glob::Pattern,
+    serde::Deserialize,
+    std::{
+        fs,
+        path::Path,
+    },
+    crate::config::ConfigError,
+};
+
+// Access manifest for the static files. Each rule maps a path glob (matched
+// against the request path, e.g. "/admin/**") to the roles allowed to fetch
+// it; the first matching rule wins. Paths without a matching rule are public.
+// A rule with an empty role list admits any authenticated user.
+//
+//     [[rules]]
+//     path = "/admin/**"
+//     roles = ["admin"]
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AccessManifest {
+    rules: Vec<(Pattern, Vec<String>)>,
+}
+
+#[derive(Deserialize)]
+struct RawManifest {
+    #[serde(default)]
+    rules: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    path: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Granted,
+    Unauthenticated,
+    Forbidden,
+}
+
+// `path` without empty or `.` segments, the way it's resolved on disk, so
+// that `/./private/x` or `//private/x` are matched like `/private/x`.
+fn normalize(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || path.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+impl AccessManifest {
+    pub fn load(path: &Path) -> Result<AccessManifest, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let raw: RawManifest = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        let rules = raw.rules.into_iter().map(|rule| {
+            Pattern::new(&rule.path)
+                .map(|pattern| (pattern, rule.roles))
+                .map_err(|err| ConfigError::Invalid(format!("Invalid path glob {:?}: {}", rule.path, err)))
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(AccessManifest { rules })
+    }
+
+    pub fn required_roles(&self, path: &str) -> Option<&[String]> {
+        let path = normalize(path);
+        self.rules.iter()
+            .find(|(pattern, _)| pattern.matches(&path))
+            .map(|(_, roles)| roles.as_slice())
+    }
+
+    // `roles` is None if the request didn't carry a valid token.
+    pub fn check(&self, path: &str, roles: Option<&[String]>) -> Access {
+        match (self.required_roles(path), roles) {
+            (None, _) => Access::Granted,
+            (Some(_), None) => Access::Unauthenticated,
+            (Some(required), Some(roles)) => {
+                if required.is_empty() || required.iter().any(|role| roles.contains(role)) {
+                    Access::Granted
+                } else {
+                    Access::Forbidden
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> AccessManifest {
+        AccessManifest {
+            rules: vec![
+                (Pattern::new("/private/**").unwrap(), vec![String::from("admin")]),
+                (Pattern::new("/members/**").unwrap(), vec![]),
+            ],
+        }
+    }
+
+    fn roles(roles: &[&str]) -> Vec<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    }
+
+    #[test]
+    fn public_paths_need_no_token() {
+        let manifest = manifest();
+        assert_eq!(manifest.check("/index.html", None), Access::Granted);
+        assert_eq!(manifest.check("/public/private/x", None), Access::Granted);
+        assert_eq!(manifest.check("/", None), Access::Granted);
+    }
+
+    #[test]
+    fn protected_paths_need_a_role() {
+        let manifest = manifest();
+        assert_eq!(manifest.check("/private/x", None), Access::Unauthenticated);
+        assert_eq!(manifest.check("/private/x", Some(&roles(&["upload"]))), Access::Forbidden);
+        assert_eq!(manifest.check("/private/x", Some(&roles(&["upload", "admin"]))), Access::Granted);
+    }
+
+    #[test]
+    fn empty_role_list_admits_any_token() {
+        let manifest = manifest();
+        assert_eq!(manifest.check("/members/x", None), Access::Unauthenticated);
+        assert_eq!(manifest.check("/members/x", Some(&[])), Access::Granted);
+    }
+
+    #[test]
+    fn matches_normalized_path() {
+        let manifest = manifest();
+        assert_eq!(manifest.check("/./private/x", None), Access::Unauthenticated);
+        assert_eq!(manifest.check("//private/x", None), Access::Unauthenticated);
+        assert_eq!(manifest.check("/private//x", Some(&roles(&["upload"]))), Access::Forbidden);
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize("/./a//b/"), "/a/b/");
+        assert_eq!(normalize("//"), "/");
+        assert_eq!(normalize("/a/."), "/a");
+    }
+}
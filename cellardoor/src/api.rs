@@ -0,0 +1,581 @@
+use {
+    log::{warn, error},
+    hyper::{
+        Body, HeaderMap, Request, Response, StatusCode,
+        header::{HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY},
+    },
+    futures::compat::Future01CompatExt,
+    tokio::prelude::{Future, Stream, stream},
+    redis::{RedisError, RedisResult, r#async::Connection},
+    rediskeys::prefixed,
+    std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    crate::{
+        config::Config,
+        error::ServeError,
+        metrics::Metrics,
+        protocol,
+        stats::Stats,
+        State,
+    },
+};
+
+// JSON API for the dashboard, serving the data the sync jobs put into Redis.
+
+pub const DESCRIPTION_PATH: &'static str = "/api";
+pub const EVENTS_PATH: &'static str = "/api/events";
+pub const SYNC_STATUS_PATH: &'static str = "/api/sync-status";
+pub const VALIDATE_COMMAND_PATH: &'static str = "/api/validate-command";
+const EVENTS_KEY: &'static str = "events";
+// Written by the sync jobs.
+const IBUTTONS_KEY: &'static str = "ibuttons";
+const LAST_SYNC_KEY: &'static str = "last_sync";
+const LAST_ERROR_KEY: &'static str = "last_error";
+// How many members to ask for per SSCAN when streaming.
+const SCAN_COUNT: usize = 100;
+// The event properties that make up the columns of the CSV representation.
+// Events are stored with whatever properties their calendar had, so these
+// are the ones worth a column; any others are left out.
+const CSV_COLUMNS: [&'static str; 6] = ["UID", "SUMMARY", "DTSTART", "DTEND", "LOCATION", "DESCRIPTION"];
+
+async fn connect(redis_url: String) -> RedisResult<Connection> {
+    let client = redis::Client::open(redis_url.as_str())?;
+    await!(client.get_async_connection().compat())
+}
+
+async fn scard(con: Connection, key: String) -> RedisResult<(Connection, usize)> {
+    await!(redis::cmd("SCARD").arg(key).query_async(con).compat())
+}
+
+async fn smembers(con: Connection, key: String) -> RedisResult<Vec<String>> {
+    let (_, members) = await!(redis::cmd("SMEMBERS").arg(key).query_async(con).compat())?;
+    Ok(members)
+}
+
+// The members of `key`, in batches read with SSCAN, so that the whole set
+// never has to be in memory at once. SSCAN may return a member more than once
+// if the set is rehashed in between; that's rare, and a duplicate event does
+// no harm in the list, so they're passed on as they come.
+fn scan_members(con: Connection, key: String) -> impl Stream<Item = Vec<String>, Error = RedisError> + Send {
+    // The first SSCAN starts at 0 like every later one, and the scan is done
+    // once Redis hands 0 back.
+    stream::unfold(Some((con, 0u64)), move |state| {
+        let (con, cursor) = state?;
+        let scan = redis::cmd("SSCAN").arg(&key).arg(cursor).arg("COUNT").arg(SCAN_COUNT).query_async(con);
+        Some(scan.map(move |(con, (cursor, members)): (Connection, (u64, Vec<String>))| {
+            (members, if cursor == 0 { None } else { Some((con, cursor)) })
+        }))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventsFormat {
+    Json,
+    Csv,
+}
+
+impl EventsFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            EventsFormat::Json => "application/json",
+            EventsFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    // The start and end of the document, and what goes between members.
+    fn header(self) -> String {
+        match self {
+            EventsFormat::Json => String::from("["),
+            EventsFormat::Csv => csv_row(CSV_COLUMNS.iter().map(|column| column.to_string())),
+        }
+    }
+
+    fn footer(self) -> &'static str {
+        match self {
+            EventsFormat::Json => "]",
+            EventsFormat::Csv => "",
+        }
+    }
+
+    fn separator(self) -> &'static str {
+        match self {
+            EventsFormat::Json => ",",
+            EventsFormat::Csv => "",
+        }
+    }
+
+    // The members are JSON objects already.
+    fn member(self, member: &str) -> String {
+        match self {
+            EventsFormat::Json => member.to_string(),
+            EventsFormat::Csv => {
+                let event = serde_json::from_str::<HashMap<String, String>>(member).unwrap_or_else(|err| {
+                    warn!("Skipping the fields of unreadable event {:?}: {}", member, err);
+                    HashMap::new()
+                });
+                csv_row(CSV_COLUMNS.iter().map(|column| event.get(*column).cloned().unwrap_or_default()))
+            },
+        }
+    }
+}
+
+// One CSV line as in RFC 4180: fields containing a separator, quote or line
+// break are quoted, with quotes doubled.
+fn csv_row<I: Iterator<Item = String>>(fields: I) -> String {
+    let mut row = fields
+        .map(|field| {
+            if field.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    row.push_str("\r\n");
+    row
+}
+
+// The q-value the Accept header(s) assign to `media_type`, from the most
+// specific entry that covers it (RFC 7231 5.3.2), if any does.
+fn media_quality(headers: &HeaderMap, media_type: &str) -> Option<f32> {
+    let main_type = media_type.split('/').next().unwrap_or("");
+    // Specificity (2 for an exact match, 1 for type/*, 0 for */*) and q.
+    let mut best: Option<(u8, f32)> = None;
+    for value in headers.get_all(ACCEPT) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for entry in value.split(',') {
+            let mut params = entry.split(';');
+            let range = params.next().unwrap_or("").trim();
+            let q = params
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            let specificity = if range.eq_ignore_ascii_case(media_type) {
+                2
+            } else if range.eq_ignore_ascii_case(&format!("{}/*", main_type)) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            if best.map(|(best, _)| specificity > best).unwrap_or(true) {
+                best = Some((specificity, q));
+            }
+        }
+    }
+    best.map(|(_, q)| q)
+}
+
+// JSON unless the client prefers CSV. Without an Accept header, anything
+// goes.
+fn events_format(headers: &HeaderMap) -> Result<EventsFormat, ServeError> {
+    if headers.get(ACCEPT).is_none() {
+        return Ok(EventsFormat::Json);
+    }
+    let json = media_quality(headers, "application/json").unwrap_or(0.0);
+    let csv = media_quality(headers, "text/csv").unwrap_or(0.0);
+    if json <= 0.0 && csv <= 0.0 {
+        Err(ServeError::NotAcceptable)
+    } else if csv > json {
+        Ok(EventsFormat::Csv)
+    } else {
+        Ok(EventsFormat::Json)
+    }
+}
+
+// Logs and counts a Redis operation that took longer than the configured
+// threshold. The time includes connecting, which is where a struggling Redis
+// tends to hang.
+fn check_slow_redis(config: &Config, metrics: &Metrics, operation: &str, key: &str, started: Instant) {
+    let elapsed = started.elapsed();
+    if config.slow_redis_ms > 0 && elapsed > Duration::from_millis(config.slow_redis_ms) {
+        warn!("Slow redis operation: {} {} took {}ms", operation, key, elapsed.as_millis());
+        metrics.slow_redis_op();
+    }
+}
+
+// Whether an If-None-Match header matches the given (strong) ETag. The
+// comparison is weak, as required for If-None-Match by RFC 7232 3.2.
+pub fn etag_matches(header: &HeaderValue, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header.to_str()
+        .map(|header| header.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        }))
+        .unwrap_or(false)
+}
+
+// The ETag of the events set, derived from its sorted members so that it
+// doesn't depend on the order Redis returns them in. The representations
+// differ, so each gets its own tag.
+fn events_etag(events: &mut Vec<String>, format: EventsFormat) -> String {
+    events.sort();
+    let mut hash = sha1::Sha1::new();
+    for event in events.iter() {
+        hash.update(event.as_bytes());
+        hash.update(b"\n");
+    }
+    match format {
+        EventsFormat::Json => format!("\"{}\"", hash.digest()),
+        EventsFormat::Csv => format!("\"{}-csv\"", hash.digest()),
+    }
+}
+
+// The events as JSON, or as CSV with the columns in `CSV_COLUMNS` if the
+// client's Accept header prefers that.
+pub async fn serve_events(req: Request<Body>, config: &Config, state: Arc<State>) -> Result<Response<Body>, ServeError> {
+    let format = events_format(req.headers())?;
+    let key = prefixed(EVENTS_KEY);
+    let metrics = &state.metrics;
+    let redis_error = |err: RedisError| {
+        error!("Failed fetching events from redis: {}", err);
+        state.stats.redis_error();
+        ServeError::Unavailable
+    };
+    let started = Instant::now();
+    let counted = match await!(connect(config.redis_url.clone())) {
+        Ok(con) => await!(scard(con, key.clone())),
+        Err(err) => Err(err),
+    };
+    check_slow_redis(config, metrics, "SCARD", &key, started);
+    let (con, count) = counted.map_err(redis_error)?;
+
+    if count > config.events_buffer_limit {
+        // The ETag would need the whole set up front, so there's none. An
+        // error ends the stream without the footer.
+        let mut emitted = false;
+        let members = scan_members(con, key.clone()).map(move |members| {
+            let mut chunk = String::new();
+            for member in members {
+                if emitted {
+                    chunk.push_str(format.separator());
+                }
+                chunk.push_str(&format.member(&member));
+                emitted = true;
+            }
+            chunk
+        });
+        let stream_state = state.clone();
+        let body = stream::once::<_, RedisError>(Ok(format.header()))
+            .chain(members)
+            .chain(stream::once(Ok(format.footer().to_string())))
+            .map_err(move |err| {
+                error!("Failed streaming {} from redis, response truncated: {}", key, err);
+                stream_state.stats.redis_error();
+                err
+            });
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, format.content_type())
+            .header(VARY, "Accept")
+            .body(Body::wrap_stream(body))?);
+    }
+
+    let started = Instant::now();
+    let events = await!(smembers(con, key.clone()));
+    check_slow_redis(config, metrics, "SMEMBERS", &key, started);
+    let mut events = events.map_err(redis_error)?;
+    let etag = events_etag(&mut events, format);
+    if req.headers().get(IF_NONE_MATCH).map(|header| etag_matches(header, &etag)).unwrap_or(false) {
+        return Ok(Response::builder().status(StatusCode::NOT_MODIFIED).header(ETAG, etag.as_str()).header(VARY, "Accept").body(Body::empty())?);
+    }
+    let members = events.iter().map(|event| format.member(event)).collect::<Vec<String>>();
+    let body = format!("{}{}{}", format.header(), members.join(format.separator()), format.footer());
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .header(ETAG, etag.as_str())
+        .header(VARY, "Accept")
+        .body(Body::from(body))?)
+}
+
+// How many iButtons and events are stored, when each sync job last ran (as
+// Unix time) and why it last failed, if it hasn't succeeded since, so that
+// operators can check that the jobs are working.
+pub async fn serve_sync_status(config: &Config, stats: &Stats, metrics: &Metrics) -> Result<Response<Body>, ServeError> {
+    let started = Instant::now();
+    let status = match await!(connect(config.redis_url.clone())) {
+        Ok(con) => await!(redis::pipe()
+            .cmd("SCARD").arg(prefixed(IBUTTONS_KEY))
+            .cmd("SCARD").arg(prefixed(EVENTS_KEY))
+            .cmd("HGETALL").arg(prefixed(LAST_SYNC_KEY))
+            .cmd("HGETALL").arg(prefixed(LAST_ERROR_KEY))
+            .query_async(con)
+            .compat()),
+        Err(err) => Err(err),
+    };
+    check_slow_redis(config, metrics, "PIPELINE", "sync status", started);
+    let (_, (ibuttons, events, last_sync, last_error)): (Connection, (usize, usize, HashMap<String, u64>, HashMap<String, String>)) = status.map_err(|err| {
+        error!("Failed fetching the sync status from redis: {}", err);
+        stats.redis_error();
+        ServeError::Unavailable
+    })?;
+    // The errors are stored as JSON already.
+    let last_error = last_error.into_iter()
+        .filter_map(|(job, error)| serde_json::from_str::<serde_json::Value>(&error).ok().map(|error| (job, error)))
+        .collect::<serde_json::Map<_, _>>();
+    let body = serde_json::json!({
+        "ibuttons": ibuttons,
+        "events": events,
+        "last_sync": last_sync,
+        "last_error": last_error,
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))?)
+}
+
+// Reads the request body, refusing it as soon as it grows beyond `limit`. A
+// declared Content-Length that's too large is refused before reading anything.
+async fn read_body(req: Request<Body>, limit: usize) -> Result<Vec<u8>, ServeError> {
+    let declared = req.headers().get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if declared.map(|length| length > limit as u64).unwrap_or(false) {
+        return Err(ServeError::PayloadTooLarge);
+    }
+    let body = req.into_body()
+        .map_err(|err| ServeError::Internal(err.to_string()))
+        .fold(Vec::new(), move |mut body, chunk| {
+            if body.len() + chunk.len() > limit {
+                return Err(ServeError::PayloadTooLarge);
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+    await!(body.compat())
+}
+
+// Machine-readable overview of the API, for integrators.
+pub fn serve_description() -> Result<Response<Body>, ServeError> {
+    let description = serde_json::json!({
+        "websocket": protocol::describe(),
+        "endpoints": [
+            { "method": "GET", "path": EVENTS_PATH, "description": "The calendar events, as a JSON array, or as CSV with Accept: text/csv." },
+            { "method": "GET", "path": SYNC_STATUS_PATH, "description": "Stored iButton and event counts and the last sync times. Needs a token." },
+            { "method": "POST", "path": VALIDATE_COMMAND_PATH, "description": "Check a WebSocket frame without executing it." },
+        ],
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(description.to_string()))?)
+}
+
+// Runs the posted frame through the WebSocket command parser without
+// executing it, so that frontend developers can check their messages.
+pub async fn validate_command(req: Request<Body>, config: &Config) -> Result<Response<Body>, ServeError> {
+    let body = await!(read_body(req, config.max_body_size))?;
+    let validation = match std::str::from_utf8(&body) {
+        Ok(text) => protocol::validate(text),
+        Err(_) => return Err(ServeError::BadRequest("Body is not valid UTF-8")),
+    };
+    let json = serde_json::to_string(&validation).map_err(|err| ServeError::Internal(err.to_string()))?;
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::tests::{block_on, test_state},
+    };
+
+    // A request whose body arrives in `chunks`, declaring `length` if given.
+    fn post(chunks: Vec<&'static str>, length: Option<&'static str>) -> Request<Body> {
+        let mut req = Request::post(VALIDATE_COMMAND_PATH);
+        if let Some(length) = length {
+            req.header(CONTENT_LENGTH, length);
+        }
+        let body = stream::iter_ok::<_, std::io::Error>(chunks.into_iter().map(|chunk| chunk.as_bytes().to_vec()));
+        req.body(Body::wrap_stream(body)).unwrap()
+    }
+
+    fn read(req: Request<Body>, limit: usize) -> Result<Vec<u8>, ServeError> {
+        block_on(async move { await!(read_body(req, limit)) })
+    }
+
+    fn is_too_large<T>(result: Result<T, ServeError>) -> bool {
+        match result {
+            Err(ServeError::PayloadTooLarge) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn reads_bodies_up_to_the_limit() {
+        assert_eq!(read(post(vec!["abc", "de"], None), 5).unwrap(), b"abcde");
+        assert_eq!(read(post(vec!["abc"], Some("3")), 3).unwrap(), b"abc");
+        assert_eq!(read(post(Vec::new(), None), 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn refuses_bodies_over_the_limit() {
+        assert!(is_too_large(read(post(vec!["abc", "def"], None), 5)));
+        // A declared length is enough, even if the body is small.
+        assert!(is_too_large(read(post(vec!["a"], Some("6")), 5)));
+        // One that's too small doesn't get the body past the limit.
+        assert!(is_too_large(read(post(vec!["abcdef"], Some("1")), 5)));
+    }
+
+    #[test]
+    fn validates_posted_commands() {
+        let config = Config::default();
+        let req = post(vec![r#"{"version": 1, "#, r#""cmd": "status"}"#], None);
+        let response = block_on(async move { await!(validate_command(req, &config)) }).unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = response.into_body().concat2().wait().unwrap();
+        let validation: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(validation["ok"], true);
+        assert_eq!(validation["command"]["cmd"], "status");
+
+        let mut config = Config::default();
+        config.max_body_size = 4;
+        let req = post(vec![r#"{"version": 1, "cmd": "status"}"#], None);
+        assert!(is_too_large(block_on(async move { await!(validate_command(req, &config)) })));
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn events_etag_ignores_the_order() {
+        let etag = events_etag(&mut strings(&["{\"UID\":\"b\"}", "{\"UID\":\"a\"}"]), EventsFormat::Json);
+        assert_eq!(etag, events_etag(&mut strings(&["{\"UID\":\"a\"}", "{\"UID\":\"b\"}"]), EventsFormat::Json));
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_ne!(etag, events_etag(&mut strings(&["{\"UID\":\"a\"}"]), EventsFormat::Json));
+        assert_ne!(etag, events_etag(&mut Vec::new(), EventsFormat::Json));
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let etag = "\"abc\"";
+        assert!(etag_matches(&HeaderValue::from_static("\"abc\""), etag));
+        assert!(etag_matches(&HeaderValue::from_static("W/\"abc\""), etag));
+        assert!(etag_matches(&HeaderValue::from_static("\"xyz\", \"abc\""), etag));
+        assert!(etag_matches(&HeaderValue::from_static("*"), etag));
+        assert!(etag_matches(&HeaderValue::from_static("\"abc\""), "W/\"abc\""));
+        assert!(!etag_matches(&HeaderValue::from_static("\"xyz\""), etag));
+        assert!(!etag_matches(&HeaderValue::from_static("abc"), etag));
+    }
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiates_the_events_format() {
+        let format = |headers: HeaderMap| events_format(&headers).ok();
+        assert_eq!(format(HeaderMap::new()), Some(EventsFormat::Json));
+        assert_eq!(format(accept("application/json")), Some(EventsFormat::Json));
+        assert_eq!(format(accept("text/csv")), Some(EventsFormat::Csv));
+        assert_eq!(format(accept("text/*")), Some(EventsFormat::Csv));
+        assert_eq!(format(accept("*/*")), Some(EventsFormat::Json));
+        assert_eq!(format(accept("application/json;q=0.5, text/csv")), Some(EventsFormat::Csv));
+        assert_eq!(format(accept("text/csv;q=0.5, */*")), Some(EventsFormat::Json));
+        assert_eq!(format(accept("*/*, text/csv;q=0")), Some(EventsFormat::Json));
+        assert_eq!(format(accept("image/png")), None);
+        assert_eq!(format(accept("text/csv;q=0, application/json;q=0")), None);
+        assert!(match events_format(&accept("image/png")) { Err(ServeError::NotAcceptable) => true, _ => false });
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_row(strings(&["plain", "a,b", "say \"hi\"", "two\nlines", ""]).into_iter()), "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\r\n");
+    }
+
+    #[test]
+    fn formats_members() {
+        let event = r#"{"UID":"1","SUMMARY":"Meetup, monthly","DTSTART":"20190605T180000","X-OTHER":"ignored"}"#;
+        assert_eq!(EventsFormat::Json.member(event), event);
+        assert_eq!(EventsFormat::Csv.member(event), "1,\"Meetup, monthly\",20190605T180000,,,\r\n");
+        assert_eq!(EventsFormat::Csv.member("not json"), ",,,,,\r\n");
+        assert_eq!(EventsFormat::Csv.header(), "UID,SUMMARY,DTSTART,DTEND,LOCATION,DESCRIPTION\r\n");
+    }
+
+    #[test]
+    fn events_etag_depends_on_the_format() {
+        let events = strings(&["{\"UID\":\"a\"}"]);
+        assert_ne!(events_etag(&mut events.clone(), EventsFormat::Json), events_etag(&mut events.clone(), EventsFormat::Csv));
+        assert!(events_etag(&mut events.clone(), EventsFormat::Csv).ends_with("-csv\""));
+    }
+
+    // Sends `replies` in order, one for each command it's sent, and hangs up
+    // once they run out.
+    fn fake_redis(replies: Vec<&'static str>) -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => return,
+            };
+            let mut buf = [0; 512];
+            for reply in replies {
+                match stream.read(&mut buf) {
+                    Ok(n) if n > 0 => (),
+                    _ => return,
+                }
+                if stream.write_all(reply.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+        format!("redis://127.0.0.1:{}/", port)
+    }
+
+    // Fetches the events with a buffer limit of 1, so that more than one
+    // event is streamed. The body is cut short if streaming fails.
+    fn stream_events(replies: Vec<&'static str>) -> (String, usize) {
+        let mut config = Config::default();
+        config.redis_url = fake_redis(replies);
+        config.events_buffer_limit = 1;
+        let state = Arc::new(test_state(config.clone()));
+        let req = Request::get(EVENTS_PATH).body(Body::empty()).unwrap();
+        block_on(async move {
+            let response = await!(serve_events(req, &config, state.clone())).unwrap();
+            let body = await!(response.into_body().concat2().compat()).map(|body| body.to_vec()).unwrap_or_default();
+            (String::from_utf8(body).unwrap(), state.stats.take().redis_errors)
+        })
+    }
+
+    #[test]
+    fn streams_large_event_sets() {
+        let (body, redis_errors) = stream_events(vec![
+            ":3\r\n",
+            "*2\r\n$1\r\n7\r\n*2\r\n$11\r\n{\"UID\":\"a\"}\r\n$11\r\n{\"UID\":\"b\"}\r\n",
+            "*2\r\n$1\r\n0\r\n*1\r\n$11\r\n{\"UID\":\"c\"}\r\n",
+        ]);
+        assert_eq!(body, r#"[{"UID":"a"},{"UID":"b"},{"UID":"c"}]"#);
+        assert_eq!(redis_errors, 0);
+    }
+
+    #[test]
+    fn counts_errors_while_streaming() {
+        let (body, redis_errors) = stream_events(vec![
+            ":3\r\n",
+            "*2\r\n$1\r\n7\r\n*2\r\n$11\r\n{\"UID\":\"a\"}\r\n$11\r\n{\"UID\":\"b\"}\r\n",
+        ]);
+        assert!(!body.ends_with(']'));
+        assert_eq!(redis_errors, 1);
+    }
+}
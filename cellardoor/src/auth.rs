@@ -0,0 +1,98 @@
+use {
+    futures::future::{self, BoxFuture, FutureExt},
+    hyper::{Body, Request, header::AUTHORIZATION},
+    crate::config::Config,
+};
+
+// Checking who a request comes from is left to an `Authenticator`, so that
+// spaces can plug in their member database, LDAP and so on. The built-in one
+// looks the bearer token up in the `tokens` of the config.
+//
+// Authenticators are asked on every request that needs roles: the WebSocket
+// upgrade, the API endpoints that need a token, uploads, and static files the
+// access manifest restricts. Slow backends should cache their answers.
+
+// What the client presented to prove who it is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Bearer(String),
+}
+
+// The roles granted by valid credentials, None if they aren't valid.
+pub type AuthResult = Option<Vec<String>>;
+
+pub trait Authenticator: Send + Sync {
+    // `config` is the config the request is served with. The future can't
+    // borrow from it; copy out whatever is needed.
+    fn verify(&self, credentials: Credentials, config: &Config) -> BoxFuture<'static, AuthResult>;
+}
+
+impl Credentials {
+    pub fn from_request(req: &Request<Body>) -> Option<Credentials> {
+        let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        if !header.starts_with("Bearer ") {
+            return None;
+        }
+        Some(Credentials::Bearer(header["Bearer ".len()..].trim().to_string()))
+    }
+}
+
+pub struct StaticTokens;
+
+impl Authenticator for StaticTokens {
+    fn verify(&self, credentials: Credentials, config: &Config) -> BoxFuture<'static, AuthResult> {
+        let roles = match credentials {
+            Credentials::Bearer(token) => config.tokens.get(&token).cloned(),
+        };
+        future::ready(roles).boxed()
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockAuthenticator {
+    pub tokens: std::collections::HashMap<String, Vec<String>>,
+}
+
+#[cfg(test)]
+impl Authenticator for MockAuthenticator {
+    fn verify(&self, credentials: Credentials, _config: &Config) -> BoxFuture<'static, AuthResult> {
+        let roles = match credentials {
+            Credentials::Bearer(token) => self.tokens.get(&token).cloned(),
+        };
+        future::ready(roles).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::tests::block_on,
+    };
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::get("/");
+        if let Some(authorization) = authorization {
+            req.header(AUTHORIZATION, authorization);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn reads_bearer_token() {
+        assert_eq!(Credentials::from_request(&request(Some("Bearer  secret "))), Some(Credentials::Bearer(String::from("secret"))));
+        assert_eq!(Credentials::from_request(&request(Some("Basic c2VjcmV0"))), None);
+        assert_eq!(Credentials::from_request(&request(None)), None);
+    }
+
+    #[test]
+    fn static_tokens_come_from_config() {
+        let mut config = Config::default();
+        config.tokens.insert(String::from("secret"), vec![String::from("admin")]);
+        let granted = StaticTokens.verify(Credentials::Bearer(String::from("secret")), &config);
+        let refused = StaticTokens.verify(Credentials::Bearer(String::from("guess")), &config);
+        assert_eq!(block_on(granted), Some(vec![String::from("admin")]));
+        assert_eq!(block_on(refused), None);
+    }
+}
@@ -1,9 +1,26 @@
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
 use tokio::io;
 use tokio::prelude::*;
+use tokio::sync::mpsc;
+use tokio::timer::Timeout;
 
 // https://jsdw.me/posts/rust-futures-tokio/
 
-pub struct ByteStream<R>(pub R);
+const CHUNK_SIZE: usize = 1024;
+
+pub struct ByteStream<R> {
+    reader: R,
+}
+
+impl <R> ByteStream<R> {
+    pub fn new(reader: R) -> Self {
+        ByteStream {
+            reader,
+        }
+    }
+}
 
 impl <R: AsyncRead> Stream for ByteStream<R> {
     type Item = Vec<u8>;
@@ -13,8 +30,8 @@ impl <R: AsyncRead> Stream for ByteStream<R> {
     // it returns an `Option<u8>` instead of a `u8`. This is so that the
     // Stream can signal that it's finished by returning `None`:
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        let mut buf = [0;1024];
-        match self.0.poll_read(&mut buf) {
+        let mut buf = [0; CHUNK_SIZE];
+        match self.reader.poll_read(&mut buf) {
             Ok(Async::Ready(n)) => {
                 // By convention, if an AsyncRead says that it read 0 bytes,
                 // we should assume that it has got to the end, so we signal that
@@ -30,3 +47,101 @@ impl <R: AsyncRead> Stream for ByteStream<R> {
         }
     }
 }
+
+// Reads `reader` on a thread of its own and streams the chunks. tokio's File
+// reads inside poll_read, so a read that hangs (NFS, SMB) would block the
+// task and never give a timer the chance to fire. Here it only blocks the
+// thread. The thread ends when the reader does or the stream is dropped.
+pub fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> impl Stream<Item = Vec<u8>, Error = io::Error> {
+    let (sender, receiver) = mpsc::channel(1);
+    thread::spawn(move || {
+        let mut sender = sender;
+        loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let chunk = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(buf)
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = chunk.is_err();
+            sender = match sender.send(chunk).wait() {
+                Ok(sender) => sender,
+                // The response was dropped.
+                Err(_) => return,
+            };
+            if failed {
+                return;
+            }
+        }
+    });
+    receiver
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        .and_then(|chunk| chunk)
+}
+
+// Fails the stream if the next chunk takes longer than `timeout` to arrive.
+pub fn with_timeout<S: Stream<Error = io::Error>>(stream: S, timeout: Duration) -> impl Stream<Item = S::Item, Error = io::Error> {
+    Timeout::new(stream, timeout).map_err(|err| {
+        if err.is_elapsed() {
+            io::Error::new(io::ErrorKind::TimedOut, "Read timed out")
+        } else if err.is_inner() {
+            err.into_inner().unwrap()
+        } else {
+            io::Error::new(io::ErrorKind::Other, err)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NeverReady;
+
+    impl std::io::Read for NeverReady {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for NeverReady {}
+
+    // Blocks inside read, like a file on a stalled network share.
+    struct Hung;
+
+    impl std::io::Read for Hung {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_secs(5));
+            Ok(0)
+        }
+    }
+
+    fn first_error<S: Stream<Item = Vec<u8>, Error = io::Error> + Send + 'static>(stream: S) -> io::ErrorKind {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(stream.collect()).err().unwrap().kind()
+    }
+
+    #[test]
+    fn times_out_reads_that_never_complete() {
+        let stream = with_timeout(ByteStream::new(NeverReady), Duration::from_millis(10));
+        assert_eq!(first_error(stream), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn times_out_reads_that_block() {
+        let stream = with_timeout(read_in_background(Hung), Duration::from_millis(10));
+        assert_eq!(first_error(stream), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reads_in_background() {
+        let data = vec![7; CHUNK_SIZE * 2 + 3];
+        let stream = with_timeout(read_in_background(std::io::Cursor::new(data.clone())), Duration::from_secs(5));
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(stream.concat2()).unwrap(), data);
+    }
+}
@@ -0,0 +1,333 @@
+use {
+    bytes::Bytes,
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    crate::config::EtagStrategy,
+};
+
+// In-memory LRU cache for small static files, so that the dashboard's assets
+// don't have to be read from disk on every request. Entries are keyed by path
+// and remember the mtime they were read at; once the file on disk has a
+// different mtime, the entry is considered stale and dropped.
+//
+// The cache also remembers the content digests of files that are too large
+// to be cached themselves, so that strong ETags don't require hashing them
+// again on every request. Those are kept for the MAX_DIGESTS most recently
+// used files.
+
+const MAX_DIGESTS: usize = 4096;
+
+#[derive(Clone)]
+pub struct CachedFile {
+    pub data: Bytes,
+    pub modified: SystemTime,
+    // Only taken once a strong ETag is asked for.
+    pub digest: Option<String>,
+}
+
+impl CachedFile {
+    pub fn etag(&self, strategy: EtagStrategy) -> String {
+        match strategy {
+            EtagStrategy::Weak => weak_etag(self.data.len() as u64, self.modified),
+            EtagStrategy::Strong => match &self.digest {
+                Some(digest) => strong_etag(digest),
+                None => strong_etag(&digest(&self.data)),
+            },
+        }
+    }
+}
+
+struct Entry {
+    file: CachedFile,
+    last_used: u64,
+}
+
+struct DigestEntry {
+    modified: SystemTime,
+    digest: String,
+    last_used: u64,
+}
+
+pub struct FileCache {
+    entries: HashMap<PathBuf, Entry>,
+    // Digests of uncached files, along with the mtime they were taken at.
+    digests: HashMap<PathBuf, DigestEntry>,
+    max_file_size: u64,
+    max_total_size: u64,
+    total_size: u64,
+    clock: u64,
+}
+
+pub fn weak_etag(size: u64, modified: SystemTime) -> String {
+    let mtime = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("W/\"{:x}-{:x}\"", size, mtime)
+}
+
+fn digest(data: &[u8]) -> String {
+    sha1::Sha1::from(data).digest().to_string()
+}
+
+pub fn strong_etag(digest: &str) -> String {
+    format!("\"{}\"", digest)
+}
+
+impl FileCache {
+    pub fn new(max_file_size: u64, max_total_size: u64) -> Self {
+        FileCache {
+            entries: HashMap::new(),
+            digests: HashMap::new(),
+            max_file_size,
+            max_total_size,
+            total_size: 0,
+            clock: 0,
+        }
+    }
+
+    // Whether a file of this size is small enough to be cached at all.
+    pub fn accepts(&self, size: u64) -> bool {
+        size <= self.max_file_size && size <= self.max_total_size
+    }
+
+    // Looks up a file, taking its digest if `strategy` needs one and the file
+    // was cached without.
+    pub fn get(&mut self, path: &Path, modified: SystemTime, strategy: EtagStrategy) -> Option<CachedFile> {
+        self.clock += 1;
+        let stale = match self.entries.get_mut(path) {
+            Some(entry) if entry.file.modified == modified => {
+                entry.last_used = self.clock;
+                if strategy == EtagStrategy::Strong && entry.file.digest.is_none() {
+                    entry.file.digest = Some(digest(&entry.file.data));
+                }
+                return Some(entry.file.clone());
+            },
+            Some(_) => true,
+            None => false,
+        };
+        if stale {
+            self.remove(path);
+        }
+        None
+    }
+
+    pub fn insert(&mut self, path: PathBuf, data: Bytes, modified: SystemTime, strategy: EtagStrategy) -> CachedFile {
+        self.remove(&path);
+        let size = data.len() as u64;
+        let file = CachedFile {
+            digest: match strategy {
+                EtagStrategy::Weak => None,
+                EtagStrategy::Strong => Some(digest(&data)),
+            },
+            data,
+            modified,
+        };
+        if !self.accepts(size) {
+            return file;
+        }
+        self.evict_until_fits(size);
+        self.clock += 1;
+        self.total_size += size;
+        self.entries.insert(path, Entry { file: file.clone(), last_used: self.clock });
+        file
+    }
+
+    pub fn digest(&mut self, path: &Path, modified: SystemTime) -> Option<String> {
+        self.clock += 1;
+        let stale = match self.digests.get_mut(path) {
+            Some(entry) if entry.modified == modified => {
+                entry.last_used = self.clock;
+                return Some(entry.digest.clone());
+            },
+            Some(_) => true,
+            None => false,
+        };
+        if stale {
+            self.digests.remove(path);
+        }
+        None
+    }
+
+    pub fn insert_digest(&mut self, path: PathBuf, modified: SystemTime, digest: String) {
+        if !self.digests.contains_key(&path) && self.digests.len() >= MAX_DIGESTS {
+            let oldest = self.digests.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.digests.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.digests.insert(path, DigestEntry { modified, digest, last_used: self.clock });
+    }
+
+    // Applies new size limits, dropping whatever no longer fits.
+    pub fn set_limits(&mut self, max_file_size: u64, max_total_size: u64) {
+        self.max_file_size = max_file_size;
+        self.max_total_size = max_total_size;
+        let too_large = self.entries.iter()
+            .filter(|(_, entry)| !self.accepts(entry.file.data.len() as u64))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in too_large {
+            self.remove(&path);
+        }
+        self.evict_until_fits(0);
+    }
+
+    fn evict_until_fits(&mut self, size: u64) {
+        while self.total_size + size > self.max_total_size {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.total_size -= entry.file.data.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use {
+        super::*,
+        std::time::Duration,
+        test::Bencher,
+    };
+
+    fn time(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(format!("/www/{}", name))
+    }
+
+    #[test]
+    fn hits_until_modified() {
+        let mut cache = FileCache::new(100, 1000);
+        cache.insert(path("a"), Bytes::from_static(b"data"), time(1), EtagStrategy::Weak);
+        assert_eq!(&cache.get(&path("a"), time(1), EtagStrategy::Weak).unwrap().data[..], b"data");
+        assert!(cache.get(&path("a"), time(2), EtagStrategy::Weak).is_none());
+        // The stale entry is gone for good.
+        assert!(cache.get(&path("a"), time(1), EtagStrategy::Weak).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = FileCache::new(10, 20);
+        cache.insert(path("a"), Bytes::from(vec![0; 10]), time(1), EtagStrategy::Weak);
+        cache.insert(path("b"), Bytes::from(vec![0; 10]), time(1), EtagStrategy::Weak);
+        cache.get(&path("a"), time(1), EtagStrategy::Weak);
+        cache.insert(path("c"), Bytes::from(vec![0; 10]), time(1), EtagStrategy::Weak);
+        assert!(cache.get(&path("a"), time(1), EtagStrategy::Weak).is_some());
+        assert!(cache.get(&path("b"), time(1), EtagStrategy::Weak).is_none());
+        assert!(cache.get(&path("c"), time(1), EtagStrategy::Weak).is_some());
+    }
+
+    #[test]
+    fn refuses_files_over_the_limit() {
+        let mut cache = FileCache::new(10, 20);
+        assert!(!cache.accepts(11));
+        let file = cache.insert(path("large"), Bytes::from(vec![0; 11]), time(1), EtagStrategy::Weak);
+        assert_eq!(file.data.len(), 11);
+        assert!(cache.get(&path("large"), time(1), EtagStrategy::Weak).is_none());
+    }
+
+    #[test]
+    fn new_limits_drop_what_no_longer_fits() {
+        let mut cache = FileCache::new(10, 20);
+        cache.insert(path("a"), Bytes::from(vec![0; 10]), time(1), EtagStrategy::Weak);
+        cache.insert(path("b"), Bytes::from(vec![0; 5]), time(1), EtagStrategy::Weak);
+        cache.set_limits(5, 20);
+        assert!(cache.get(&path("a"), time(1), EtagStrategy::Weak).is_none());
+        assert!(cache.get(&path("b"), time(1), EtagStrategy::Weak).is_some());
+    }
+
+    #[test]
+    fn etags_follow_strategy() {
+        let mut cache = FileCache::new(10, 20);
+        let file = cache.insert(path("a"), Bytes::from_static(b"abc"), time(16), EtagStrategy::Weak);
+        assert_eq!(file.etag(EtagStrategy::Weak), "W/\"3-10\"");
+        assert_eq!(file.etag(EtagStrategy::Strong), "\"a9993e364706816aba3e25717850c26c9cd0d89d\"");
+    }
+
+    #[test]
+    fn digests_only_for_strong_etags() {
+        let mut cache = FileCache::new(10, 20);
+        assert!(cache.insert(path("a"), Bytes::from_static(b"abc"), time(1), EtagStrategy::Weak).digest.is_none());
+        assert!(cache.get(&path("a"), time(1), EtagStrategy::Weak).unwrap().digest.is_none());
+        assert_eq!(cache.get(&path("a"), time(1), EtagStrategy::Strong).unwrap().digest, Some(String::from("a9993e364706816aba3e25717850c26c9cd0d89d")));
+        assert!(cache.insert(path("b"), Bytes::from_static(b"abc"), time(1), EtagStrategy::Strong).digest.is_some());
+    }
+
+    #[test]
+    fn digests_follow_mtime() {
+        let mut cache = FileCache::new(10, 20);
+        cache.insert_digest(path("large"), time(1), String::from("digest"));
+        assert_eq!(cache.digest(&path("large"), time(1)), Some(String::from("digest")));
+        assert_eq!(cache.digest(&path("large"), time(2)), None);
+        assert_eq!(cache.digest(&path("large"), time(1)), None);
+    }
+
+    #[test]
+    fn digests_are_bounded() {
+        let mut cache = FileCache::new(10, 20);
+        for index in 0..MAX_DIGESTS {
+            cache.insert_digest(path(&index.to_string()), time(1), index.to_string());
+        }
+        // Keeps the first one in use, so that the second is the oldest.
+        assert!(cache.digest(&path("0"), time(1)).is_some());
+        cache.insert_digest(path("new"), time(1), String::from("new"));
+        assert_eq!(cache.digests.len(), MAX_DIGESTS);
+        assert!(cache.digest(&path("0"), time(1)).is_some());
+        assert!(cache.digest(&path("1"), time(1)).is_none());
+        assert!(cache.digest(&path("new"), time(1)).is_some());
+    }
+
+    fn filled_cache(files: usize) -> FileCache {
+        let mut cache = FileCache::new(64 * 1024, files as u64 * 4096);
+        for index in 0..files {
+            cache.insert(path(&index.to_string()), Bytes::from(vec![0; 4096]), time(1), EtagStrategy::Weak);
+        }
+        cache
+    }
+
+    #[bench]
+    fn bench_get(b: &mut Bencher) {
+        let mut cache = filled_cache(256);
+        let path = path("128");
+        b.iter(|| cache.get(&path, time(1), EtagStrategy::Weak));
+    }
+
+    #[bench]
+    fn bench_insert_with_eviction(b: &mut Bencher) {
+        let mut cache = filled_cache(256);
+        let data = Bytes::from(vec![0; 4096]);
+        let mut index = 0;
+        b.iter(|| {
+            index += 1;
+            cache.insert(path(&format!("new-{}", index)), data.clone(), time(1), EtagStrategy::Weak)
+        });
+    }
+
+    #[bench]
+    fn bench_digest(b: &mut Bencher) {
+        let mut cache = FileCache::new(0, 0);
+        for index in 0..MAX_DIGESTS {
+            cache.insert_digest(path(&index.to_string()), time(1), index.to_string());
+        }
+        let path = path("2048");
+        b.iter(|| cache.digest(&path, time(1)));
+    }
+}
@@ -0,0 +1,109 @@
+use hyper::{
+    Body, Response,
+    header::{HeaderValue, CONTENT_TYPE},
+};
+
+// Declares UTF-8 on textual responses, so that browsers don't have to guess
+// the encoding of non-ASCII content.
+
+const CHARSET: &'static str = "charset=utf-8";
+// How much of a file `sniff` wants to see.
+pub const SNIFF_LEN: usize = 512;
+
+// Guesses the type of a file that has no telling extension from its first
+// bytes: UTF-8 without control characters is text, anything else binary.
+// `truncated` says whether `data` was cut off at `SNIFF_LEN` rather than
+// being the whole file.
+pub fn sniff(data: &[u8], truncated: bool) -> &'static str {
+    let valid = match std::str::from_utf8(data) {
+        Ok(_) => true,
+        // A cut-off sample may end in the middle of a character.
+        Err(err) => truncated && err.error_len().is_none(),
+    };
+    let control = data.iter().any(|&byte| byte < 0x20 && !b"\t\n\r\x0c\x1b".contains(&byte));
+    if valid && !control {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+pub fn is_textual(mimetype: &str) -> bool {
+    let essence = mimetype.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || ["application/javascript", "application/json", "application/xml"].contains(&essence)
+}
+
+pub fn add_charset(response: &mut Response<Body>) {
+    let content_type = match response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) {
+        Some(content_type) if is_textual(content_type) && !content_type.contains("charset=") => format!("{}; {}", content_type, CHARSET),
+        _ => return,
+    };
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        response.headers_mut().insert(CONTENT_TYPE, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_text_and_binary() {
+        assert_eq!(sniff(b"Hello, world!\r\n\tIndented\n", false), "text/plain; charset=utf-8");
+        assert_eq!(sniff("Grüße".as_bytes(), false), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"", false), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\x00", false), "application/octet-stream");
+        assert_eq!(sniff(b"caf\xe9", false), "application/octet-stream");
+    }
+
+    #[test]
+    fn sniffing_allows_a_cut_off_character() {
+        let text = "Grüße".as_bytes();
+        assert_eq!(sniff(&text[..3], true), "text/plain; charset=utf-8");
+        // The whole file can't end in one.
+        assert_eq!(sniff(&text[..3], false), "application/octet-stream");
+        assert_eq!(sniff(b"caf\xe9", true), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn recognizes_textual_types() {
+        assert!(is_textual("text/html"));
+        assert!(is_textual("application/json"));
+        assert!(is_textual("image/svg+xml"));
+        assert!(is_textual("text/plain; charset=utf-8"));
+        assert!(!is_textual("image/png"));
+        assert!(!is_textual("application/octet-stream"));
+    }
+
+    // The Content-Type `add_charset` leaves on a response of `content_type`.
+    fn with_charset(content_type: Option<&'static str>) -> Option<String> {
+        let mut response = Response::new(Body::empty());
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        add_charset(&mut response);
+        response.headers().get(CONTENT_TYPE).map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn adds_charset_to_textual_types() {
+        assert_eq!(with_charset(Some("text/html")), Some(String::from("text/html; charset=utf-8")));
+        assert_eq!(with_charset(Some("application/json")), Some(String::from("application/json; charset=utf-8")));
+    }
+
+    #[test]
+    fn keeps_an_existing_charset() {
+        assert_eq!(with_charset(Some("text/plain; charset=iso-8859-1")), Some(String::from("text/plain; charset=iso-8859-1")));
+        assert_eq!(with_charset(Some("text/csv; charset=utf-8")), Some(String::from("text/csv; charset=utf-8")));
+    }
+
+    #[test]
+    fn leaves_other_types_alone() {
+        assert_eq!(with_charset(Some("image/png")), Some(String::from("image/png")));
+        assert_eq!(with_charset(Some("application/octet-stream")), Some(String::from("application/octet-stream")));
+        assert_eq!(with_charset(None), None);
+    }
+}
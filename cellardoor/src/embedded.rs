@@ -0,0 +1,115 @@
+use {
+    bytes::Bytes,
+    std::collections::{HashMap, HashSet},
+};
+
+// The frontend compiled into the binary (the "embedded" feature, bundling
+// the assets directory next to Cargo.toml), for deployments that consist of the
+// executable alone. Files in the static root take precedence, so single
+// assets can still be replaced on disk.
+//
+// Everything is hashed once at startup, so that embedded files always get
+// strong ETags: they have no mtime to build a weak one from.
+
+#[cfg(feature = "embedded")]
+static ASSETS: include_dir::Dir = include_dir::include_dir!("assets");
+
+#[derive(Clone)]
+pub struct EmbeddedFile {
+    pub data: Bytes,
+    pub digest: String,
+}
+
+#[derive(Default)]
+pub struct Bundle {
+    // Keyed by path below the root, with a leading /.
+    files: HashMap<String, EmbeddedFile>,
+    dirs: HashSet<String>,
+}
+
+impl Bundle {
+    #[cfg(feature = "embedded")]
+    pub fn load() -> Self {
+        let mut bundle = Bundle::default();
+        bundle.add_dir(&ASSETS);
+        bundle
+    }
+
+    #[cfg(not(feature = "embedded"))]
+    pub fn load() -> Self {
+        Bundle::default()
+    }
+
+    #[cfg(feature = "embedded")]
+    fn add_dir(&mut self, dir: &include_dir::Dir<'static>) {
+        for file in dir.files() {
+            let mut hash = sha1::Sha1::new();
+            hash.update(file.contents());
+            self.files.insert(format!("/{}", file.path().to_string_lossy()), EmbeddedFile {
+                data: Bytes::from_static(file.contents()),
+                digest: hash.digest().to_string(),
+            });
+        }
+        for subdir in dir.dirs() {
+            self.dirs.insert(format!("/{}", subdir.path().to_string_lossy()));
+            self.add_dir(subdir);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn get(&self, path: &str) -> Option<EmbeddedFile> {
+        self.files.get(path).cloned()
+    }
+
+    pub fn is_file(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+
+    // `path` without a trailing /.
+    pub fn is_dir(&self, path: &str) -> bool {
+        self.dirs.contains(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "embedded")]
+    #[test]
+    fn bundles_the_assets() {
+        let bundle = Bundle::load();
+        let index = bundle.get("/index.html").expect("index.html is bundled");
+        assert_eq!(&index.data[..], &include_bytes!("../assets/index.html")[..]);
+        let mut hash = sha1::Sha1::new();
+        hash.update(&index.data);
+        assert_eq!(index.digest, hash.digest().to_string());
+    }
+
+    #[cfg(not(feature = "embedded"))]
+    #[test]
+    fn empty_without_the_feature() {
+        assert_eq!(Bundle::load().len(), 0);
+    }
+
+    #[test]
+    fn looks_up_files_and_dirs() {
+        let mut bundle = Bundle::default();
+        bundle.files.insert(String::from("/js/app.js"), EmbeddedFile {
+            data: Bytes::from_static(b"app"),
+            digest: String::from("digest"),
+        });
+        bundle.dirs.insert(String::from("/js"));
+
+        assert_eq!(bundle.len(), 1);
+        assert!(bundle.is_file("/js/app.js"));
+        assert!(!bundle.is_file("/js"));
+        assert!(bundle.is_dir("/js"));
+        assert!(!bundle.is_dir("/js/app.js"));
+        assert_eq!(&bundle.get("/js/app.js").unwrap().data[..], b"app");
+        assert!(bundle.get("/missing").is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use {
+    hyper::{
+        HeaderMap,
+        header::ACCEPT_ENCODING,
+    },
+    std::path::{Path, PathBuf},
+};
+
+// Content negotiation for precompressed static files. Build pipelines put
+// `foo.js.br` and `foo.js.gz` next to `foo.js`; if the client accepts the
+// encoding, we serve the sibling instead of the original.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+// In order of preference.
+const ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    // The path of the precompressed sibling of `path`.
+    pub fn sibling(self, path: &Path) -> PathBuf {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(self.extension());
+        PathBuf::from(sibling)
+    }
+}
+
+// The q-value the Accept-Encoding header(s) assign to `name`, if it's
+// mentioned at all, either by name or by the `*` wildcard.
+fn quality(headers: &HeaderMap, name: &str) -> Option<f32> {
+    let mut wildcard = None;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for entry in value.split(',') {
+            let mut params = entry.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let q = params
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            if coding.eq_ignore_ascii_case(name) {
+                return Some(q);
+            } else if coding == "*" {
+                wildcard = Some(q);
+            }
+        }
+    }
+    wildcard
+}
+
+// The encodings the client accepts, most preferred first.
+pub fn accepted(headers: &HeaderMap) -> Vec<Encoding> {
+    ENCODINGS.iter()
+        .cloned()
+        .filter(|encoding| quality(headers, encoding.name()).map(|q| q > 0.0).unwrap_or(false))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        hyper::header::HeaderValue,
+    };
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn siblings_get_the_extension() {
+        assert_eq!(Encoding::Brotli.sibling(Path::new("/www/app.js")), PathBuf::from("/www/app.js.br"));
+        assert_eq!(Encoding::Gzip.sibling(Path::new("/www/app.js")), PathBuf::from("/www/app.js.gz"));
+    }
+
+    #[test]
+    fn accepts_encodings_in_order_of_preference() {
+        assert_eq!(accepted(&HeaderMap::new()), vec![]);
+        assert_eq!(accepted(&accept_encoding("gzip, br")), vec![Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(accepted(&accept_encoding("GZIP")), vec![Encoding::Gzip]);
+        assert_eq!(accepted(&accept_encoding("br;q=0, gzip;q=0.5")), vec![Encoding::Gzip]);
+        assert_eq!(accepted(&accept_encoding("*")), vec![Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(accepted(&accept_encoding("*;q=0, br")), vec![Encoding::Brotli]);
+        assert_eq!(accepted(&accept_encoding("identity")), vec![]);
+    }
+}
@@ -0,0 +1,180 @@
+use {
+    log::error,
+    hyper::{
+        Body, Response, StatusCode,
+        header::{ALLOW, CONTENT_TYPE, SEC_WEBSOCKET_VERSION, WWW_AUTHENTICATE},
+        http,
+    },
+    std::io,
+};
+
+// Everything that can go wrong while handling a request. Handlers return
+// these via `?`, and `into_response` turns them into the reply sent to the
+// client, so that the mapping to status codes lives in one place.
+
+#[derive(Debug)]
+pub enum ServeError {
+    BadRequest(&'static str),
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    // With the methods the resource does allow.
+    MethodNotAllowed(&'static str),
+    NotAcceptable,
+    NotImplemented,
+    ExpectationFailed,
+    PayloadTooLarge,
+    // Something else is being done to the resource at the same time.
+    Conflict,
+    UnsupportedWebSocketVersion,
+    Unavailable,
+    Internal(String),
+}
+
+impl From<io::Error> for ServeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => ServeError::NotFound,
+            io::ErrorKind::PermissionDenied => ServeError::Forbidden,
+            _ => ServeError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<http::Error> for ServeError {
+    fn from(err: http::Error) -> Self {
+        ServeError::Internal(err.to_string())
+    }
+}
+
+impl ServeError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServeError::BadRequest(_) | ServeError::UnsupportedWebSocketVersion => StatusCode::BAD_REQUEST,
+            ServeError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServeError::Forbidden => StatusCode::FORBIDDEN,
+            ServeError::NotFound => StatusCode::NOT_FOUND,
+            ServeError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ServeError::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ServeError::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            ServeError::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            ServeError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServeError::Conflict => StatusCode::CONFLICT,
+            ServeError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServeError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut response = Response::builder();
+        response.status(self.status());
+        response.header(CONTENT_TYPE, "text/plain");
+        let body = match self {
+            ServeError::BadRequest(message) => message,
+            ServeError::Unauthorized => {
+                response.header(WWW_AUTHENTICATE, "Bearer");
+                "Unauthorized"
+            },
+            ServeError::Forbidden => "Forbidden",
+            ServeError::NotFound => "Not Found",
+            ServeError::MethodNotAllowed(allowed) => {
+                response.header(ALLOW, allowed);
+                return response.body(Body::from(format!("Method Not Allowed, use one of {}", allowed))).unwrap();
+            },
+            ServeError::NotAcceptable => "Not Acceptable",
+            ServeError::NotImplemented => "Not Implemented",
+            ServeError::ExpectationFailed => "Only 100-continue is supported",
+            ServeError::PayloadTooLarge => "Payload Too Large",
+            ServeError::Conflict => "Conflict",
+            ServeError::UnsupportedWebSocketVersion => {
+                // RFC 6455 4.4: tell the client which version we do support.
+                response.header(SEC_WEBSOCKET_VERSION, "13");
+                "Unknown WebSocket version"
+            },
+            ServeError::Unavailable => "Service Unavailable",
+            ServeError::Internal(err) => {
+                error!("{}", err);
+                "Internal Server Error"
+            },
+        };
+        response.body(Body::from(body)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::prelude::{Future, Stream},
+    };
+
+    fn body(response: Response<Body>) -> String {
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allowed_methods() {
+        let response = ServeError::MethodNotAllowed("GET, HEAD, OPTIONS").into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD, OPTIONS");
+        assert_eq!(body(response), "Method Not Allowed, use one of GET, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn unauthorized_asks_for_bearer_token() {
+        let response = ServeError::Unauthorized.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[test]
+    fn unsupported_websocket_version_names_ours() {
+        let response = ServeError::UnsupportedWebSocketVersion.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[SEC_WEBSOCKET_VERSION], "13");
+    }
+
+    #[test]
+    fn expectation_failed_names_what_is_supported() {
+        let response = ServeError::ExpectationFailed.into_response();
+        assert_eq!(response.status(), StatusCode::EXPECTATION_FAILED);
+        assert_eq!(body(response), "Only 100-continue is supported");
+    }
+
+    #[test]
+    fn not_implemented_has_no_allow_header() {
+        let response = ServeError::NotImplemented.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert!(response.headers().get(ALLOW).is_none());
+        assert_eq!(body(response), "Not Implemented");
+    }
+
+    #[test]
+    fn not_acceptable() {
+        let response = ServeError::NotAcceptable.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(body(response), "Not Acceptable");
+    }
+
+    #[test]
+    fn internal_errors_are_not_leaked() {
+        let response = ServeError::Internal(String::from("secret detail")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(response), "Internal Server Error");
+    }
+
+    #[test]
+    fn maps_io_errors() {
+        assert_eq!(ServeError::from(io::Error::from(io::ErrorKind::NotFound)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(ServeError::from(io::Error::from(io::ErrorKind::PermissionDenied)).status(), StatusCode::FORBIDDEN);
+        assert_eq!(ServeError::from(io::Error::from(io::ErrorKind::Other)).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn bad_request_carries_its_reason() {
+        let response = ServeError::BadRequest("Invalid path").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response), "Invalid path");
+    }
+}
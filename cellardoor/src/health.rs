@@ -0,0 +1,147 @@
+use {
+    log::{info, warn, error},
+    hyper::{Body, Response, StatusCode},
+    futures::compat::Future01CompatExt,
+    redis::RedisResult,
+    std::sync::atomic::{AtomicBool, Ordering},
+    crate::{
+        config::Config,
+        error::ServeError,
+    },
+};
+
+// Startup self-test of the services we depend on, so that misconfiguration
+// shows up at boot instead of on the first request that needs them. /ready
+// checks them again on every request, so that it also reports Redis going
+// away (or coming back) later on.
+
+pub const READY_PATH: &'static str = "/ready";
+
+#[cfg_attr(test, derive(Default))]
+pub struct Health {
+    redis: AtomicBool,
+}
+
+async fn ping_redis(redis_url: String) -> RedisResult<()> {
+    let client = redis::Client::open(redis_url.as_str())?;
+    let con = await!(client.get_async_connection().compat())?;
+    let (_, _pong): (_, String) = await!(redis::cmd("PING").query_async(con).compat())?;
+    Ok(())
+}
+
+impl Health {
+    pub async fn self_test(config: &Config) -> Health {
+        let redis = match await!(ping_redis(config.redis_url.clone())) {
+            Ok(()) => {
+                info!("Self-test: redis is reachable.");
+                true
+            },
+            Err(err) => {
+                error!("Self-test: redis is unreachable: {}", err);
+                false
+            }
+        };
+        Health {
+            redis: AtomicBool::new(redis),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.redis.load(Ordering::Relaxed)
+    }
+
+    // Pings Redis again, logging if that changes the outcome.
+    async fn recheck(&self, config: &Config) -> bool {
+        let result = await!(ping_redis(config.redis_url.clone()));
+        let reachable = result.is_ok();
+        if self.redis.swap(reachable, Ordering::Relaxed) != reachable {
+            match result {
+                Ok(()) => info!("Redis is reachable again."),
+                Err(err) => error!("Redis has become unreachable: {}", err),
+            }
+        } else if let Err(err) = result {
+            warn!("Redis is still unreachable: {}", err);
+        }
+        reachable
+    }
+
+    pub async fn serve_ready(&self, config: &Config) -> Result<Response<Body>, ServeError> {
+        if await!(self.recheck(config)) {
+            Ok(Response::builder().body(Body::from("Ready"))?)
+        } else {
+            Ok(Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("Degraded: redis is unreachable"))?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::tests::block_on,
+    };
+
+    // Whether /ready answers 200 with Redis at `redis_url`, and the flag
+    // afterwards.
+    fn ready(health: Health, redis_url: &str) -> (StatusCode, bool) {
+        let mut config = Config::default();
+        config.redis_url = redis_url.to_string();
+        block_on(async move {
+            let status = await!(health.serve_ready(&config)).unwrap().status();
+            (status, health.is_ready())
+        })
+    }
+
+    #[test]
+    fn ready_reports_redis_going_away() {
+        let health = Health { redis: AtomicBool::new(true) };
+        assert!(health.is_ready());
+        // Nothing listens on port 1.
+        assert_eq!(ready(health, "redis://127.0.0.1:1/"), (StatusCode::SERVICE_UNAVAILABLE, false));
+    }
+
+    #[test]
+    fn self_test_fails_without_redis() {
+        let mut config = Config::default();
+        config.redis_url = String::from("redis://127.0.0.1:1/");
+        let health = block_on(async move { await!(Health::self_test(&config)) });
+        assert!(!health.is_ready());
+        assert_eq!(ready(health, "redis://127.0.0.1:1/"), (StatusCode::SERVICE_UNAVAILABLE, false));
+    }
+
+    // Answers PONG to whatever it's sent, which is all the ping needs.
+    fn fake_redis() -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut buf = [0; 512];
+                while let Ok(n) = stream.read(&mut buf) {
+                    if n == 0 || stream.write_all(b"+PONG\r\n").is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        format!("redis://127.0.0.1:{}/", port)
+    }
+
+    #[test]
+    fn ready_reports_redis_coming_back() {
+        let health = Health { redis: AtomicBool::new(false) };
+        assert_eq!(ready(health, &fake_redis()), (StatusCode::OK, true));
+    }
+
+    #[test]
+    fn self_test_passes_with_redis() {
+        let mut config = Config::default();
+        config.redis_url = fake_redis();
+        let health = block_on(async move { await!(Health::self_test(&config)) });
+        assert!(health.is_ready());
+    }
+}
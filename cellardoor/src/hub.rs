@@ -0,0 +1,124 @@
+use {
+    futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    std::{
+        collections::HashMap,
+        sync::{
+            Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::{Duration, Instant},
+    },
+    websocket::{CloseData, message::OwnedMessage},
+    crate::protocol::SessionInfo,
+};
+
+// Keeps track of all connected WebSocket sessions, so that messages can be
+// sent to every one of them. Each session registers when the connection is
+// upgraded and gets a channel on which the hub delivers its messages. The hub
+// also remembers when each session was last heard from, so that dead
+// connections can be swept.
+
+pub type SessionId = usize;
+
+// Status codes for "going away" and "policy violation", see RFC 6455 7.4.1.
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+struct Session {
+    sender: UnboundedSender<OwnedMessage>,
+    last_activity: Instant,
+    // Opened with a token granting the admin role.
+    admin: bool,
+}
+
+pub struct Hub {
+    sessions: Mutex<HashMap<SessionId, Session>>,
+    next_id: AtomicUsize,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub {
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn register(&self, admin: bool) -> (SessionId, UnboundedReceiver<OwnedMessage>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded();
+        self.sessions.lock().unwrap().insert(id, Session {
+            sender,
+            last_activity: Instant::now(),
+            admin,
+        });
+        (id, receiver)
+    }
+
+    pub fn unregister(&self, id: SessionId) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    // Records that the session was just heard from.
+    pub fn touch(&self, id: SessionId) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.last_activity = Instant::now();
+        }
+    }
+
+    // Closes and forgets all sessions that haven't been heard from for
+    // longer than `idle_timeout`. Returns how many there were.
+    pub fn sweep(&self, idle_timeout: Duration) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let stale = sessions.iter()
+            .filter(|(_, session)| session.last_activity.elapsed() > idle_timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in &stale {
+            if let Some(session) = sessions.remove(id) {
+                let _ = session.sender.unbounded_send(OwnedMessage::Close(Some(CloseData::new(CLOSE_GOING_AWAY, String::from("Idle timeout")))));
+            }
+        }
+        stale.len()
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions = self.sessions.lock().unwrap().iter()
+            .map(|(id, session)| SessionInfo {
+                id: *id,
+                admin: session.admin,
+                idle: session.last_activity.elapsed().as_secs(),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    // Closes and forgets one session. Returns false if there's no such
+    // session.
+    pub fn evict(&self, id: SessionId) -> bool {
+        match self.sessions.lock().unwrap().remove(&id) {
+            Some(session) => {
+                let _ = session.sender.unbounded_send(OwnedMessage::Close(Some(CloseData::new(CLOSE_POLICY_VIOLATION, String::from("Evicted")))));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn broadcast(&self, message: OwnedMessage) {
+        for session in self.sessions.lock().unwrap().values() {
+            // A failure only means that the session is on its way out.
+            let _ = session.sender.unbounded_send(message.clone());
+        }
+    }
+
+    // Tells every session that the server is going away.
+    pub fn close_all(&self) {
+        self.broadcast(OwnedMessage::Close(Some(CloseData::new(CLOSE_GOING_AWAY, String::from("Server shutting down")))));
+    }
+}
@@ -0,0 +1,60 @@
+use hyper::header::HeaderValue;
+
+// Makes client-controlled text safe to put into a log line: control
+// characters (newlines in particular, which could forge log entries) are
+// escaped, and overly long text is cut short at a character boundary.
+
+pub fn sanitize(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut sanitized = String::with_capacity(end);
+    for c in text[..end].chars() {
+        if c.is_control() {
+            sanitized.extend(c.escape_default());
+        } else {
+            sanitized.push(c);
+        }
+    }
+    if end < text.len() {
+        sanitized.push_str(&format!("... ({} bytes)", text.len()));
+    }
+    sanitized
+}
+
+// A header value for the log, "-" if the header is missing. Values needn't be
+// UTF-8, anything else is replaced.
+pub fn header(value: Option<&HeaderValue>, max_len: usize) -> String {
+    match value {
+        Some(value) => sanitize(&String::from_utf8_lossy(value.as_bytes()), max_len),
+        None => String::from("-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(sanitize("/index.html", 256), "/index.html");
+        assert_eq!(sanitize("/a\r\nGET /forged", 256), "/a\\r\\nGET /forged");
+        assert_eq!(sanitize("/\u{1b}[31mred", 256), "/\\u{1b}[31mred");
+    }
+
+    #[test]
+    fn cuts_long_text_at_a_character_boundary() {
+        assert_eq!(sanitize("/abcdef", 4), "/abc... (7 bytes)");
+        assert_eq!(sanitize("grüße", 3), "gr... (7 bytes)");
+        assert_eq!(sanitize("grüße", 7), "grüße");
+    }
+
+    #[test]
+    fn logs_header_values() {
+        assert_eq!(header(None, 256), "-");
+        assert_eq!(header(Some(&HeaderValue::from_static("https://example.com")), 256), "https://example.com");
+        assert_eq!(header(Some(&HeaderValue::from_bytes(b"caf\xe9").unwrap()), 256), "caf\u{fffd}");
+        assert_eq!(header(Some(&HeaderValue::from_static("https://example.com")), 8), "https://... (19 bytes)");
+    }
+}
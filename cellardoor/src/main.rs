@@ -1,133 +1,966 @@
 #![feature(await_macro, async_await, futures_api)]
+#![cfg_attr(test, feature(test))]
 use {
-    log::{info, debug, error},
+    log::{log, info, debug, warn, error},
     hyper::{
         // Miscellaneous types from Hyper for working with HTTP.
-        Body, Request, Response, Server, StatusCode, Method,
+        Body, HeaderMap, Request, Response, Server, StatusCode, Method,
 
         // This function turns a closure which returns a future into an
         // implementation of the the Hyper `Service` trait, which is an
         // asynchronous function from a generic `Request` to a `Response`.
         service::service_fn,
 
-        header::{HeaderValue, UPGRADE, CONTENT_TYPE, CONNECTION, SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_ACCEPT},
-        upgrade::Upgraded,
+        header::{HeaderName, HeaderValue, ACCEPT_RANGES, ALLOW, UPGRADE, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, CONNECTION, ETAG, EXPECT, IF_NONE_MATCH, IF_RANGE, LOCATION, ORIGIN, RANGE, VARY, SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_ACCEPT},
+        http,
     },
     futures::{
         // Extension traits providing additional methods on futures.
         // `FutureExt` adds methods that work for all futures, whereas
         // `TryFutureExt` adds methods to futures that return `Result` types.
-        future::{FutureExt, TryFutureExt},
-        stream::StreamExt,
+        future::{self, FutureExt, TryFutureExt},
+        stream::{self, StreamExt},
         compat::{Stream01CompatExt, Future01CompatExt},
     },
-    std::net::SocketAddr,
 
     tokio::{
         // This is the redefinition of the await! macro which supports both
         // futures 0.1 (used by Hyper and Tokio) and futures 0.3 (the new API
         // exposed by `std::future` and implemented by `async fn` syntax).
         fs::file::File,
-        codec::{Decoder, Framed},
+        codec::Decoder,
+        prelude::{Async, Future, Sink, Stream, future as future01, stream as stream01},
+        timer::Delay,
     },
     std::{
+        io,
+        net::{SocketAddr, TcpListener},
         path::{Path, PathBuf},
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::{Duration, Instant, SystemTime},
     },
+    arc_swap::ArcSwap,
+    tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM},
+    bytes::Bytes,
     mime_guess::get_mime_type_str,
     websocket::{
         r#async::{MessageCodec, MsgCodecCtx},
         message::OwnedMessage,
+        result::WebSocketError,
     },
 };
 
+mod access;
+mod api;
+mod auth;
 mod byte_stream;
+mod cache;
+mod config;
+mod content_type;
+mod embedded;
+mod encoding;
+mod error;
+mod health;
+mod hub;
+mod log_text;
+mod metrics;
+mod protocol;
+mod range;
+mod request_path;
+mod semaphore;
+mod signing;
+mod stats;
+mod upload;
 
-const STATIC_FILES: &'static str = "/www";
+use {
+    access::Access,
+    auth::{Authenticator, Credentials, StaticTokens},
+    cache::{FileCache, CachedFile},
+    config::{Config, EtagStrategy},
+    embedded::Bundle,
+    encoding::Encoding,
+    error::ServeError,
+    health::Health,
+    hub::{Hub, SessionId},
+    metrics::{Direction, Metrics},
+    protocol::{ClientMessage, ServerMessage, ADMIN_ROLE},
+    range::ByteRange,
+    semaphore::{Permit, Semaphore},
+    signing::NonceCache,
+    stats::Stats,
+};
+
+// The methods each kind of resource supports, for Allow. Hyper leaves out
+// the body when answering HEAD, so it's served like GET.
+const READ_METHODS: &'static str = "GET, HEAD, OPTIONS";
+const COMMAND_METHODS: &'static str = "POST, OPTIONS";
+const UPLOAD_METHODS: &'static str = "PUT, OPTIONS";
 const WEBSOCKET_MAGIC: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Minimal dashboard, served for / as long as the static root has no
+// index.html of its own.
+const BUILTIN_UI: &'static str = include_str!("../assets/index.html");
+// How long WebSocket sessions get to send their close frames on shutdown.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// Everything shared between requests.
+pub struct State {
+    pub config: ArcSwap<Config>,
+    pub cache: Mutex<FileCache>,
+    pub authenticator: Box<dyn Authenticator>,
+    // The embedded frontend, empty without the "embedded" feature.
+    pub embedded: Bundle,
+    pub hub: Hub,
+    pub health: Health,
+    pub stats: Stats,
+    pub metrics: Metrics,
+    // Limits the number of files open at the same time.
+    pub open_files: Semaphore,
+    // The uploads that are being received.
+    pub uploads: upload::InProgress,
+    // Limits the number of connected WebSocket sessions.
+    pub ws_sessions: Semaphore,
+    // Nonces of recently signed WebSocket commands.
+    pub nonces: NonceCache,
+    // Successful requests so far, for sampling the request log.
+    pub successful_requests: AtomicUsize,
+}
 
-async fn serve_ws(framed: Framed<Upgraded, MessageCodec<OwnedMessage>>) {
-    let mut framed = framed.compat();
-    while let Some(message) = await!(framed.next()) {
-        debug!("Received message: {:?}", message);
+impl State {
+    fn new(config: Config, health: Health, embedded: Bundle) -> Self {
+        State {
+            cache: Mutex::new(FileCache::new(config.cache.max_file_size, config.cache.max_total_size)),
+            embedded,
+            authenticator: Box::new(StaticTokens),
+            hub: Hub::new(),
+            health,
+            stats: Stats::default(),
+            metrics: Metrics::new(&config.latency_buckets),
+            open_files: Semaphore::new(config.max_open_files),
+            uploads: upload::InProgress::default(),
+            ws_sessions: Semaphore::new(config.max_ws_sessions),
+            nonces: NonceCache::default(),
+            successful_requests: AtomicUsize::new(0),
+            config: ArcSwap::from(Arc::new(config)),
+        }
     }
 }
 
-async fn serve_req(req: Request<Body>, mut root: PathBuf) -> Result<Response<Body>, hyper::Error> {
-    info!("REQ {} {}", req.method(), req.uri());
-    if req.method() == Method::GET {
-        if req.headers().contains_key(UPGRADE) {
-            debug!("Upgrade to websocket!");
+enum SessionEvent {
+    Incoming(Result<OwnedMessage, WebSocketError>),
+    Outgoing(OwnedMessage),
+    Disconnected,
+}
 
-            if Some(&HeaderValue::from_static("13")) == req.headers().get(SEC_WEBSOCKET_VERSION) {
-                if let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) {
-                    let mut hash = sha1::Sha1::new();
-                    hash.update(key.as_bytes());
-                    hash.update(WEBSOCKET_MAGIC.as_bytes());
-                    let accept_str = base64::encode(&hash.digest().bytes());
-
-                    tokio::spawn((async move {
-                        if let Ok(upgraded) = await!(req.into_body().on_upgrade().compat()) {
-                            await!(serve_ws(MessageCodec::default(MsgCodecCtx::Server).framed(upgraded)));
-                        } else {
-                            error!("WebSocket upgrade failed.");
-                        }
-                        Ok(())
-                    }).boxed().compat());
-
-                    Ok(Response::builder().status(StatusCode::SWITCHING_PROTOCOLS)
-                        .header(UPGRADE, "websocket")
-                        .header(CONNECTION, "Upgrade")
-                        .header(SEC_WEBSOCKET_ACCEPT, accept_str)
-                        .body(Body::from("Switching protocols")).unwrap())
-                } else {
-                    Ok(Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("Missing Sec-WebSocket-Key")).unwrap())
-                }
+// `roles` are the roles of the session's token, checked against the role the
+// command requires.
+fn handle_command(command: ClientMessage, state: &State, id: SessionId, roles: &[String]) -> ServerMessage {
+    debug!("Command: {:?}", command);
+    if let Some(role) = command.role() {
+        if !roles.iter().any(|granted| granted == role) {
+            debug!("Session {} lacks the {} role for {}", id, role, command.name());
+            return ServerMessage::Error { message: String::from("forbidden") };
+        }
+    }
+    match command {
+        ClientMessage::Sessions => ServerMessage::Sessions { sessions: state.hub.list() },
+        ClientMessage::Evict { session } => {
+            if state.hub.evict(session) {
+                info!("Session {} evicted by session {}", session, id);
+                ServerMessage::Ok
             } else {
-                Ok(Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("Unknown WebSocket version")).unwrap())
+                ServerMessage::Error { message: format!("No session {}", session) }
+            }
+        },
+        _ => ServerMessage::Error { message: String::from("Door control is not available") },
+    }
+}
+
+// `roles` are the roles granted by the token the session was opened with,
+// which decide the commands it may use. `permit` is the session's slot of
+// `max_ws_sessions`, given back once the session is over. The connection is
+// usually an upgraded HTTP connection, framed with `MessageCodec`.
+async fn serve_ws<C>(connection: C, state: Arc<State>, roles: Vec<String>, permit: Permit)
+    where C: Stream<Item = OwnedMessage, Error = WebSocketError> + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError> + Send + 'static
+{
+    let (id, outgoing) = state.hub.register(roles.iter().any(|role| role == ADMIN_ROLE));
+    state.stats.ws_session(state.hub.len());
+    let (mut sink, stream) = connection.split();
+    let incoming = stream.compat()
+        .map(SessionEvent::Incoming)
+        .chain(stream::once(future::ready(SessionEvent::Disconnected)));
+    let mut events = stream::select(incoming, outgoing.map(SessionEvent::Outgoing));
+    while let Some(event) = await!(events.next()) {
+        if let SessionEvent::Incoming(ref message) = event {
+            state.hub.touch(id);
+            if let Ok(message) = message {
+                state.metrics.ws_message(Direction::Received, message);
+            }
+        }
+        let message = match event {
+            SessionEvent::Incoming(Ok(OwnedMessage::Text(text))) => {
+                debug!("Received message: {:?}", text);
+                let (reply, correlation_id) = match protocol::parse_envelope(&text) {
+                    Ok(envelope) => {
+                        state.metrics.ws_command(envelope.message.name());
+                        let config = state.config.load();
+                        let verified = match config.command_secret {
+                            Some(ref secret) => signing::verify(&envelope, secret, config.command_max_age, &state.nonces),
+                            None => Ok(()),
+                        };
+                        match verified {
+                            Ok(()) => (handle_command(envelope.message, &state, id, &roles), envelope.correlation_id),
+                            Err(reason) => {
+                                warn!("Session {}: refusing {} command: {}", id, envelope.message.name(), reason);
+                                (ServerMessage::Error { message: reason.to_string() }, envelope.correlation_id)
+                            },
+                        }
+                    },
+                    Err(err) => {
+                        state.metrics.ws_command("invalid");
+                        (ServerMessage::Error { message: err.to_string() }, protocol::correlation_id(&text))
+                    },
+                };
+                OwnedMessage::Text(protocol::encode(reply, correlation_id))
+            },
+            SessionEvent::Incoming(Ok(OwnedMessage::Close(_))) | SessionEvent::Disconnected => break,
+            SessionEvent::Incoming(Ok(message)) => {
+                debug!("Received message: {:?}", message);
+                continue;
+            },
+            SessionEvent::Incoming(Err(err)) => {
+                error!("WebSocket error: {}", err);
+                break;
+            },
+            SessionEvent::Outgoing(message) => message,
+        };
+        let closing = message.is_close();
+        state.metrics.ws_message(Direction::Sent, &message);
+        match await!(sink.send(message).compat()) {
+            Ok(s) => sink = s,
+            Err(err) => {
+                error!("Failed sending WebSocket message: {}", err);
+                break;
+            }
+        }
+        if closing {
+            break;
+        }
+    }
+    state.hub.unregister(id);
+    drop(permit);
+}
+
+// Whether any of the (comma-separated) values of `name` contains `token`.
+// Tokens of the Upgrade header may carry a version, as in "websocket/13".
+fn header_has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().split('/').next().unwrap_or(""))
+        .any(|value| value.eq_ignore_ascii_case(token))
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    header_has_token(headers, CONNECTION, "upgrade") && header_has_token(headers, UPGRADE, "websocket")
+}
+
+// Roles granted by the request's credentials, if it carries valid ones.
+async fn request_roles(req: &Request<Body>, config: &Config, state: &State) -> Option<Vec<String>> {
+    let credentials = Credentials::from_request(req)?;
+    await!(state.authenticator.verify(credentials, config))
+}
+
+// Waits for one of the `max_open_files` slots to become free.
+async fn open_file_permit(state: &State) -> Permit {
+    match state.open_files.try_acquire() {
+        Some(permit) => permit,
+        None => {
+            warn!("Open file limit reached, waiting for a free slot.");
+            await!(state.open_files.acquire())
+        }
+    }
+}
+
+// Looks up the file in the cache, (re)reading it from disk if it's missing or
+// the mtime has changed. Returns None if the file is too large to be cached.
+async fn cached_file(state: Arc<State>, path: PathBuf, strategy: EtagStrategy) -> Option<CachedFile> {
+    let metadata = await!(tokio::fs::metadata(path.clone()).compat()).ok()?;
+    let modified = metadata.modified().ok()?;
+    let cached = state.cache.lock().unwrap().get(&path, modified, strategy);
+    if cached.is_some() {
+        return cached;
+    }
+    if !state.cache.lock().unwrap().accepts(metadata.len()) {
+        return None;
+    }
+    let permit = await!(open_file_permit(&state));
+    let data = await!(tokio::fs::read(path.clone()).compat()).ok()?;
+    drop(permit);
+    debug!("Caching file {:?}", path);
+    Some(state.cache.lock().unwrap().insert(path, Bytes::from(data), modified, strategy))
+}
+
+// Hashes a file that's too large for the cache, reading it in chunks. The
+// digest is remembered until the file's mtime changes. Returns None (and the
+// file goes without an ETag) if it can't be read.
+async fn file_digest(state: Arc<State>, path: PathBuf, modified: SystemTime) -> Option<String> {
+    if let Some(digest) = state.cache.lock().unwrap().digest(&path, modified) {
+        return Some(digest);
+    }
+    let permit = await!(open_file_permit(&state));
+    let file = await!(File::open(path.clone()).compat()).ok()?;
+    let hash = byte_stream::ByteStream::new(file).fold(sha1::Sha1::new(), |mut hash, chunk| {
+        hash.update(&chunk);
+        Ok::<_, std::io::Error>(hash)
+    });
+    let hash = await!(hash.compat()).map_err(|err| {
+        error!("Hashing {:?} failed: {}", path, err);
+    }).ok()?;
+    drop(permit);
+    let digest = hash.digest().to_string();
+    state.cache.lock().unwrap().insert_digest(path, modified, digest.clone());
+    Some(digest)
+}
+
+// Reads the start of a file to guess its content type.
+async fn sniff_file(state: Arc<State>, path: PathBuf) -> Option<&'static str> {
+    let permit = await!(open_file_permit(&state));
+    let file = await!(File::open(path).compat()).ok()?;
+    let (_, data, len) = await!(tokio::io::read(file, vec![0; content_type::SNIFF_LEN]).compat()).ok()?;
+    drop(permit);
+    Some(content_type::sniff(&data[..len], len == content_type::SNIFF_LEN))
+}
+
+// Whether the client already has the version tagged `etag`.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get(IF_NONE_MATCH).map(|header| api::etag_matches(header, etag)).unwrap_or(false)
+}
+
+// Sends a file from disk (or the cache), labelled with the content type of
+// the file that was originally requested. `headers` are the request's, for
+// conditional and range requests. `received` is when the request came in,
+// for the latency metrics.
+async fn serve_file(state: Arc<State>, config: Arc<Config>, path: PathBuf, mimetype: Option<&'static str>, encoding: Option<Encoding>, headers: &HeaderMap, received: Instant) -> Result<Response<Body>, ServeError> {
+    let mut response = Response::builder();
+    response.header(VARY, "Accept-Encoding");
+    if let Some(mimetype) = mimetype {
+        response.header(CONTENT_TYPE, mimetype);
+    }
+    if let Some(encoding) = encoding {
+        response.header(CONTENT_ENCODING, encoding.name());
+    }
+    if config.cache.enabled {
+        if let Some(file) = await!(cached_file(state.clone(), path.clone(), config.etag)) {
+            let etag = file.etag(config.etag);
+            return serve_data(&state, response, file.data, &etag, headers, received);
+        }
+    }
+    // Same validator as the cache uses, so that it doesn't matter which of
+    // the two served the file. This happens before taking the permit for
+    // serving, since hashing the file needs one of its own.
+    let metadata = await!(tokio::fs::metadata(path.clone()).compat());
+    if let Ok((len, modified)) = metadata.and_then(|metadata| Ok((metadata.len(), metadata.modified()?))) {
+        let etag = match config.etag {
+            EtagStrategy::Weak => Some(cache::weak_etag(len, modified)),
+            EtagStrategy::Strong => await!(file_digest(state.clone(), path.clone(), modified)).map(|digest| cache::strong_etag(&digest)),
+        };
+        if let Some(etag) = etag {
+            response.header(ETAG, etag.as_str());
+            if not_modified(headers, &etag) {
+                response.status(StatusCode::NOT_MODIFIED);
+                return Ok(response.body(Body::empty())?);
+            }
+        }
+    }
+    let permit = await!(open_file_permit(&state));
+    let file = await!(File::open(path.clone()).compat()).map_err(|err| {
+        // Missing files are mostly favicons and probes, anything else is a
+        // problem with the static root.
+        if err.kind() == io::ErrorKind::NotFound {
+            log!(config.not_found_log_level, "{:?}: {}", path, err);
+        } else {
+            error!("{:?}: {}", path, err);
+        }
+        err
+    })?;
+    // Hyper would send a stream as chunked, even an empty one, which some
+    // clients trip over. An empty file doesn't need one.
+    let (file, metadata) = await!(file.metadata().compat())?;
+    if metadata.len() == 0 {
+        let elapsed = received.elapsed();
+        state.metrics.disk_first_byte.observe(elapsed);
+        state.metrics.disk_complete.observe(elapsed);
+        response.header(CONTENT_LENGTH, "0");
+        return Ok(response.body(Body::empty())?);
+    }
+    let stream: Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send> = match config.read_timeout {
+        0 => Box::new(byte_stream::ByteStream::new(file)),
+        timeout => Box::new(byte_stream::with_timeout(byte_stream::read_in_background(file.into_std()), Duration::from_secs(timeout))),
+    };
+    let mut first_chunk = true;
+    let completion_state = state.clone();
+    let stream = stream
+        .inspect(move |chunk| {
+            // The permit has to live as long as the file is open, i.e. as
+            // long as the stream.
+            let _ = &permit;
+            if first_chunk {
+                state.metrics.disk_first_byte.observe(received.elapsed());
+                first_chunk = false;
+            }
+            state.stats.bytes(chunk.len());
+        })
+        // Only reached if the whole file was read, so aborted responses
+        // don't count as completed.
+        .chain(stream01::poll_fn(move || {
+            completion_state.metrics.disk_complete.observe(received.elapsed());
+            Ok(Async::Ready(None))
+        }))
+        .map_err(move |err| {
+            error!("Reading {:?} failed: {}", path, err);
+            err
+        });
+    Ok(response.body(Body::wrap_stream(stream))?)
+}
+
+// Sends a file that is already in memory, from the cache or the embedded
+// bundle. Ranges are only supported for these, since they can be sliced
+// right out of memory.
+fn serve_data(state: &State, mut response: http::response::Builder, data: Bytes, etag: &str, headers: &HeaderMap, received: Instant) -> Result<Response<Body>, ServeError> {
+    response.header(ETAG, etag);
+    response.header(ACCEPT_RANGES, "bytes");
+    // The body is handed to hyper in one piece, so the first and the last
+    // byte are ready at the same time.
+    let elapsed = received.elapsed();
+    state.metrics.cache_first_byte.observe(elapsed);
+    state.metrics.cache_complete.observe(elapsed);
+    if not_modified(headers, etag) {
+        response.status(StatusCode::NOT_MODIFIED);
+        return Ok(response.body(Body::empty())?);
+    }
+    let len = data.len() as u64;
+    // A client resuming with a copy of another version gets the whole file.
+    let range = if range::if_range_matches(headers.get(IF_RANGE), etag) {
+        headers.get(RANGE)
+    } else {
+        None
+    };
+    match range::parse(range, len) {
+        ByteRange::Full => {
+            state.stats.bytes(data.len());
+            Ok(response.body(Body::from(data))?)
+        },
+        ByteRange::Partial(start, end) => {
+            let data = data.slice(start as usize, end as usize + 1);
+            state.stats.bytes(data.len());
+            response.status(StatusCode::PARTIAL_CONTENT);
+            response.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len).as_str());
+            Ok(response.body(Body::from(data))?)
+        },
+        ByteRange::Unsatisfiable => {
+            response.status(StatusCode::RANGE_NOT_SATISFIABLE);
+            response.header(CONTENT_RANGE, format!("bytes */{}", len).as_str());
+            Ok(response.body(Body::empty())?)
+        },
+    }
+}
+
+// Sends a file from the embedded bundle, with the same headers `serve_file`
+// would have used for it.
+fn serve_embedded(state: &State, config: &Config, file: embedded::EmbeddedFile, mimetype: Option<&'static str>, headers: &HeaderMap, received: Instant) -> Result<Response<Body>, ServeError> {
+    let mimetype = match mimetype {
+        None if config.sniff_content_type => {
+            let truncated = file.data.len() > content_type::SNIFF_LEN;
+            Some(content_type::sniff(&file.data[..file.data.len().min(content_type::SNIFF_LEN)], truncated))
+        },
+        mimetype => mimetype,
+    };
+    let mut response = Response::builder();
+    response.header(VARY, "Accept-Encoding");
+    if let Some(mimetype) = mimetype {
+        response.header(CONTENT_TYPE, mimetype);
+    }
+    serve_data(state, response, file.data, &cache::strong_etag(&file.digest), headers, received)
+}
+
+// Hyper answers `Expect: 100-continue` by itself, but only once the handler
+// starts reading the body. Requests that are refused never get that far, so
+// the client learns about the refusal without having sent the body. Any other
+// expectation is refused outright (RFC 7231 5.1.1).
+fn check_expectation(req: &Request<Body>) -> Result<(), ServeError> {
+    match req.headers().get(EXPECT) {
+        Some(expect) if !expect.to_str().map(|expect| expect.trim().eq_ignore_ascii_case("100-continue")).unwrap_or(false) => {
+            Err(ServeError::ExpectationFailed)
+        },
+        _ => Ok(()),
+    }
+}
+
+// The methods the resource at `path` supports.
+fn allowed_methods(path: &str, config: &Config) -> &'static str {
+    if path == api::VALIDATE_COMMAND_PATH {
+        COMMAND_METHODS
+    } else if path.starts_with(upload::UPLOAD_PREFIX) && config.upload_dir.is_some() {
+        UPLOAD_METHODS
+    } else {
+        READ_METHODS
+    }
+}
+
+async fn route(req: Request<Body>, config: Arc<Config>, state: Arc<State>, received: Instant) -> Result<Response<Body>, ServeError> {
+    check_expectation(&req)?;
+    let allowed = allowed_methods(req.uri().path(), &config);
+    if req.method() == Method::OPTIONS {
+        Ok(Response::builder().status(StatusCode::NO_CONTENT).header(ALLOW, allowed).body(Body::empty())?)
+    } else if req.method() == Method::POST && allowed == COMMAND_METHODS {
+        await!(api::validate_command(req, &config))
+    } else if req.method() == Method::PUT && allowed == UPLOAD_METHODS {
+        let path = request_path::decode(req.uri().path())?;
+        let roles = await!(request_roles(&req, &config, &state)).ok_or(ServeError::Unauthorized)?;
+        let rule = upload::authorize(&config, &path, &roles)?;
+        await!(upload::receive(req, &config, &path, rule.max_size, &state.open_files, &state.uploads))
+    } else if (req.method() == Method::GET || req.method() == Method::HEAD) && allowed == READ_METHODS {
+        // Refused before any of the branches below gets to look at it.
+        let path = request_path::decode(req.uri().path())?;
+        if req.method() == Method::GET && is_websocket_upgrade(req.headers()) {
+            debug!("Upgrade to websocket!");
+
+            if Some(&HeaderValue::from_static("13")) != req.headers().get(SEC_WEBSOCKET_VERSION) {
+                warn!("Unsupported WebSocket version {} from origin {}",
+                    log_text::header(req.headers().get(SEC_WEBSOCKET_VERSION), config.log_uri_max_len),
+                    log_text::header(req.headers().get(ORIGIN), config.log_uri_max_len));
+                return Err(ServeError::UnsupportedWebSocketVersion);
             }
+            let key = req.headers().get(SEC_WEBSOCKET_KEY).ok_or(ServeError::BadRequest("Missing Sec-WebSocket-Key"))?;
+            let mut hash = sha1::Sha1::new();
+            hash.update(key.as_bytes());
+            hash.update(WEBSOCKET_MAGIC.as_bytes());
+            let accept_str = base64::encode(&hash.digest().bytes());
+            let roles = await!(request_roles(&req, &config, &state)).unwrap_or_default();
+            // Taken last, once the upgrade is known to go ahead, and held
+            // for as long as the session lasts.
+            let permit = match state.ws_sessions.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    warn!("WebSocket session limit reached, refusing upgrade.");
+                    return Err(ServeError::Unavailable);
+                }
+            };
+
+            // The session only joins the hub in `serve_ws`, so if the upgrade
+            // fails (e.g. the client hangs up right after the 101), there's
+            // nothing to unregister, and the permit is returned on drop.
+            tokio::spawn((async move {
+                match await!(req.into_body().on_upgrade().compat()) {
+                    Ok(upgraded) => await!(serve_ws(MessageCodec::default(MsgCodecCtx::Server).framed(upgraded), state, roles, permit)),
+                    Err(err) => {
+                        error!("WebSocket upgrade failed: {}", err);
+                        state.metrics.ws_failed_upgrade();
+                    },
+                }
+                Ok(())
+            }).boxed().compat());
 
+            Ok(Response::builder().status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(UPGRADE, "websocket")
+                .header(CONNECTION, "Upgrade")
+                .header(SEC_WEBSOCKET_ACCEPT, accept_str)
+                .body(Body::from("Switching protocols"))?)
+        } else if req.uri().path() == health::READY_PATH {
+            await!(state.health.serve_ready(&config))
+        } else if req.uri().path() == metrics::METRICS_PATH {
+            state.metrics.serve_metrics()
+        } else if req.uri().path() == api::DESCRIPTION_PATH {
+            api::serve_description()
+        } else if req.uri().path() == api::SYNC_STATUS_PATH {
+            if await!(request_roles(&req, &config, &state)).is_none() {
+                return Err(ServeError::Unauthorized);
+            }
+            await!(api::serve_sync_status(&config, &state.stats, &state.metrics))
+        } else if req.uri().path() == api::EVENTS_PATH {
+            await!(api::serve_events(req, &config, state.clone()))
         } else {
-            let filename = req.uri().path();
-            root.push(&filename[1..]); // remove leading /
+            let filename = path.as_str();
+            if config.hide_dotfiles && is_dotfile(filename) && !config.dotfile_prefixes.iter().any(|prefix| filename.starts_with(prefix.as_str())) {
+                debug!("Refusing dotfile {}", log_text::sanitize(filename, config.log_uri_max_len));
+                return Err(ServeError::NotFound);
+            }
+            // Only files the manifest restricts need the authenticator.
+            let access = match config.access.check(filename, None) {
+                Access::Unauthenticated => {
+                    let roles = await!(request_roles(&req, &config, &state));
+                    config.access.check(filename, roles.as_ref().map(|roles| roles.as_slice()))
+                },
+                access => access,
+            };
+            match access {
+                Access::Granted => {},
+                Access::Unauthenticated => return Err(ServeError::Unauthorized),
+                Access::Forbidden => return Err(ServeError::Forbidden),
+            }
+            if config.canonical_redirects {
+                if let Some(location) = await!(canonical_path(&config, &state.embedded, filename, req.uri().path())) {
+                    let location = match req.uri().query() {
+                        Some(query) => format!("{}?{}", location, query),
+                        None => location,
+                    };
+                    return Ok(Response::builder()
+                        .status(StatusCode::MOVED_PERMANENTLY)
+                        .header(LOCATION, location.as_str())
+                        .body(Body::empty())?);
+                }
+            }
+            let is_root = filename == "/";
+            let filename = if filename.ends_with('/') {
+                await!(index_file(&config, &state.embedded, filename))
+            } else {
+                filename.to_string()
+            };
+            let filename = filename.as_str();
+            let root = request_path::join(&config.static_root, filename).ok_or(ServeError::BadRequest("Invalid path"))?;
             let extension = &(Path::new(filename).extension().and_then(|s| s.to_str()));
             debug!("Requesting file {:?}", root.to_str());
-            match await!(File::open(root.into_boxed_path()).compat()) {
-                Ok(file) => {
-                    let mut response = Response::builder();
-                    if let Some(mimetype) = extension.and_then(|ref extension| get_mime_type_str(&extension)) {
-                        response.header(CONTENT_TYPE, mimetype);
+            let mimetype = extension.and_then(|ref extension| get_mime_type_str(&extension));
+            for encoding in encoding::accepted(req.headers()) {
+                let sibling = encoding.sibling(&root);
+                if await!(tokio::fs::metadata(sibling.clone()).compat()).map(|metadata| metadata.is_file()).unwrap_or(false) {
+                    return await!(serve_file(state, config, sibling, mimetype, Some(encoding), req.headers(), received));
+                }
+            }
+            let mimetype = match mimetype {
+                None if config.sniff_content_type => await!(sniff_file(state.clone(), root.clone())),
+                mimetype => mimetype,
+            };
+            let builtin_ui = config.builtin_ui;
+            match await!(serve_file(state.clone(), config.clone(), root, mimetype, None, req.headers(), received)) {
+                Err(ServeError::NotFound) => {
+                    if let Some(file) = state.embedded.get(filename) {
+                        serve_embedded(&state, &config, file, mimetype, req.headers(), received)
+                    } else if is_root && builtin_ui {
+                        Ok(Response::builder().header(CONTENT_TYPE, "text/html").body(Body::from(BUILTIN_UI))?)
+                    } else {
+                        Err(ServeError::NotFound)
                     }
-                    Ok(response.body(Body::wrap_stream(byte_stream::ByteStream(file))).unwrap())
                 },
-                Err(err) => {
-                    error!("{}", err);
-                    Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found")).unwrap())
-                }
+                result => result,
             }
         }
+    } else if is_standard_method(req.method()) {
+        Err(ServeError::MethodNotAllowed(allowed))
     } else {
-        Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::from("Only GET Allowed!")).unwrap())
+        // RFC 7231 4.1: methods the server doesn't recognize at all get 501,
+        // known ones that the resource doesn't support 405.
+        Err(ServeError::NotImplemented)
+    }
+}
+
+// Where to redirect a request to, if its (decoded) `path` names a directory
+// without a trailing slash or a file with one, on disk or in the embedded
+// bundle. The location is built from the path as it was sent, so that it
+// stays percent-encoded. `path` has been through `request_path::decode`.
+async fn canonical_path(config: &Config, bundle: &Bundle, path: &str, raw_path: &str) -> Option<String> {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return None;
+    }
+    let target = request_path::join(&config.static_root, trimmed)?;
+    let (is_dir, is_file) = match await!(tokio::fs::metadata(target).compat()) {
+        Ok(metadata) => (metadata.is_dir(), metadata.is_file()),
+        Err(_) => (bundle.is_dir(trimmed), bundle.is_file(trimmed)),
+    };
+    if is_dir && !path.ends_with('/') {
+        Some(redirect_location(raw_path, true))
+    } else if is_file && path.ends_with('/') {
+        Some(redirect_location(raw_path, false))
+    } else {
+        None
+    }
+}
+
+// `raw_path` with or without a trailing slash. Leading slashes are collapsed
+// into one, since a location starting with `//` would point to another host.
+fn redirect_location(raw_path: &str, trailing_slash: bool) -> String {
+    let path = raw_path.trim_start_matches('/').trim_end_matches('/');
+    if trailing_slash {
+        format!("/{}/", path)
+    } else {
+        format!("/{}", path)
+    }
+}
+
+// The first of the configured index files that exists in the directory
+// `path` (relative to the static root, with a trailing /), on disk or in the
+// embedded bundle, or the first one if there's none, so that the request ends
+// up as a 404.
+async fn index_file(config: &Config, bundle: &Bundle, path: &str) -> String {
+    for name in &config.index_files {
+        let index = format!("{}{}", path, name);
+        let on_disk = match request_path::join(&config.static_root, &index) {
+            Some(candidate) => await!(tokio::fs::metadata(candidate).compat()).map(|metadata| metadata.is_file()).unwrap_or(false),
+            None => false,
+        };
+        if on_disk || bundle.is_file(&index) {
+            return index;
+        }
+    }
+    format!("{}{}", path, config.index_files[0])
+}
+
+fn is_dotfile(path: &str) -> bool {
+    path.split('/').any(|component| component.starts_with('.'))
+}
+
+fn is_standard_method(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::DELETE, Method::CONNECT, Method::OPTIONS, Method::TRACE, Method::PATCH].contains(method)
+}
+
+// Adds the configured extra headers, without replacing any that the
+// response already has.
+fn add_extra_headers(response: &mut Response<Body>, config: &Config) {
+    if config.extra_headers_html_only {
+        let is_html = response.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("text/html"))
+            .unwrap_or(false);
+        if !is_html {
+            return;
+        }
+    }
+    let headers = response.headers_mut();
+    for (name, value) in &config.extra_headers {
+        // Both were validated when the config was loaded.
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            headers.entry(name).unwrap().or_insert(value);
+        }
+    }
+}
+
+async fn serve_req(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, hyper::Error> {
+    let received = Instant::now();
+    state.stats.request();
+    let config = state.config.load();
+    let (method, uri) = (req.method().clone(), req.uri().clone());
+    let mut response = await!(route(req, config.clone(), state.clone(), received)).unwrap_or_else(ServeError::into_response);
+    let log = if response.status().is_success() {
+        let count = state.successful_requests.fetch_add(1, Ordering::Relaxed);
+        config.request_log_sample > 0 && count % config.request_log_sample == 0
+    } else {
+        true
+    };
+    if log {
+        info!("REQ {} {} {}", method, log_text::sanitize(&uri.to_string(), config.log_uri_max_len), response.status().as_u16());
+    }
+    add_extra_headers(&mut response, &config);
+    if config.add_charset {
+        content_type::add_charset(&mut response);
+    }
+    Ok(response)
+}
+
+// Re-reads the config file whenever we receive SIGHUP. The new config is only
+// applied if it validates; requests already in flight keep the config they
+// started with, since they hold their own reference to it.
+async fn reload_on_sighup(state: Arc<State>) {
+    let signals = match await!(Signal::new(SIGHUP).compat()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("Failed to install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+    let mut signals = signals.compat();
+    while let Some(_) = await!(signals.next()) {
+        let path = Config::path();
+        info!("Reloading configuration from {:?}", path);
+        match Config::load(&path) {
+            Ok(new_config) => {
+                state.config.load().log_changes(&new_config);
+                state.cache.lock().unwrap().set_limits(new_config.cache.max_file_size, new_config.cache.max_total_size);
+                state.config.store(Arc::new(new_config));
+            },
+            Err(err) => {
+                error!("{}, keeping the current configuration.", err);
+            }
+        }
+    }
+}
+
+// Logs a summary of the server's activity every `stats_interval` seconds.
+async fn log_stats(state: Arc<State>) {
+    loop {
+        let interval = state.config.load().stats_interval;
+        if interval == 0 {
+            return;
+        }
+        if await!(Delay::new(Instant::now() + Duration::from_secs(interval)).compat()).is_err() {
+            return;
+        }
+        let summary = state.stats.take();
+        info!("Stats for the last {}s: {} requests, {} bytes served, {} WebSocket connections, {} redis errors",
+            interval, summary.requests, summary.bytes, state.hub.len(), summary.redis_errors);
+    }
+}
+
+// Periodically closes WebSocket sessions that haven't sent anything for
+// longer than `ws_idle_timeout` seconds, as a safety net against dead
+// connections that were never noticed. Each sweep pings the remaining
+// sessions, so live clients always have something (a pong) to answer.
+async fn sweep_sessions(state: Arc<State>) {
+    loop {
+        let (interval, idle_timeout) = {
+            let config = state.config.load();
+            (config.ws_sweep_interval, config.ws_idle_timeout)
+        };
+        if interval == 0 {
+            return;
+        }
+        if await!(Delay::new(Instant::now() + Duration::from_secs(interval)).compat()).is_err() {
+            return;
+        }
+        let swept = state.hub.sweep(Duration::from_secs(idle_timeout));
+        if swept > 0 {
+            info!("Closed {} idle WebSocket sessions, {} remaining.", swept, state.hub.len());
+        }
+        state.hub.broadcast(OwnedMessage::Ping(Vec::new()));
+    }
+}
+
+// Resolves once we receive SIGINT or SIGTERM. If the handlers can't be
+// installed, it never resolves, rather than shutting down right away; the
+// server then only stops when it's killed.
+async fn shutdown_signal() {
+    let (sigint, sigterm) = match await!(Signal::new(SIGINT).join(Signal::new(SIGTERM)).compat()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("Failed to install SIGINT/SIGTERM handlers, graceful shutdown is unavailable: {}", err);
+            let _ = await!(future01::empty::<(), ()>().compat());
+            return;
+        }
+    };
+    let _ = await!(sigint.select(sigterm).into_future().compat());
+}
+
+// Socket activation: systemd opens the listening socket and passes it on as
+// file descriptor 3, so that cellardoor can listen on a privileged port
+// without running as root, and is only started on the first connection.
+// Needs the "systemd" feature and a socket unit next to the service, e.g.
+//
+//     # cellardoor.socket
+//     [Socket]
+//     ListenStream=80
+//
+//     [Install]
+//     WantedBy=sockets.target
+//
+// Without a socket from systemd, `listen` is bound as usual.
+#[cfg(feature = "systemd")]
+fn activated_listener() -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    // The first descriptor systemd passes, see sd_listen_fds(3).
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only using the first.", fds);
     }
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
 }
 
-async fn run_server(addr: SocketAddr) -> Result<(), hyper::error::Error> {
+#[cfg(not(feature = "systemd"))]
+fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+// `Server::bind` always listens with the default backlog, so the socket is
+// set up here instead.
+fn bind_listener(addr: &SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let builder = if addr.is_ipv4() { net2::TcpBuilder::new_v4()? } else { net2::TcpBuilder::new_v6()? };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    builder.listen(backlog)
+}
+
+async fn run_server(config: Config) -> Result<(), hyper::error::Error> {
+    let addr = config.listen;
+    config.log_banner();
     info!("Listening on http://{}", addr);
 
-    // Create a server bound on the provided address
-    let serve_future = Server::bind(&addr)
-        // Serve requests using our `async serve_req` function.
-        // `serve` takes a closure which returns a type implementing the
-        // `Service` trait. `service_fn` returns a value implementing the
-        // `Service` trait, and accepts a closure which goes from request
-        // to a future of the response. In order to use our `serve_req`
-        // function with Hyper, we have to box it and put it in a compatability
-        // wrapper to go from a futures 0.3 future (the kind returned by
-        // `async fn`) to a futures 0.1 future (the kind used by Hyper).
-        .serve(|| service_fn(|req| serve_req(req, PathBuf::from(STATIC_FILES)).boxed().compat()));
+    let health = await!(Health::self_test(&config));
+    if !health.is_ready() && config.require_ready {
+        error!("Self-test failed, refusing to serve.");
+        std::process::exit(1);
+    }
+
+    let listener = match activated_listener() {
+        Some(listener) => {
+            // systemd already chose the backlog (Backlog= in the socket unit).
+            info!("Using the listening socket passed by systemd instead.");
+            listener
+        },
+        None => bind_listener(&addr, config.listen_backlog).unwrap_or_else(|err| panic!("Failed listening on {}: {}", addr, err)),
+    };
+    let tcp_nodelay = config.tcp_nodelay;
+    let tcp_keepalive = Some(Duration::from_secs(config.tcp_keepalive)).filter(|keepalive| *keepalive > Duration::from_secs(0));
+
+    let embedded = Bundle::load();
+    if embedded.len() > 0 {
+        info!("Serving {} embedded files where the static root has none.", embedded.len());
+    }
+
+    let state = Arc::new(State::new(config, health, embedded));
+
+    tokio::spawn(reload_on_sighup(state.clone()).unit_error().boxed().compat());
+    tokio::spawn(log_stats(state.clone()).unit_error().boxed().compat());
+    tokio::spawn(sweep_sessions(state.clone()).unit_error().boxed().compat());
+
+    // On shutdown, WebSocket clients are told that we're going away, rather
+    // than just having the connection drop.
+    let shutdown_state = state.clone();
+    let shutdown = async move {
+        await!(shutdown_signal());
+        info!("Shutting down, closing {} WebSocket sessions.", shutdown_state.hub.len());
+        shutdown_state.hub.close_all();
+        let _ = await!(Delay::new(Instant::now() + SHUTDOWN_GRACE_PERIOD).compat());
+        let lifetime = shutdown_state.stats.lifetime();
+        info!("Up for {}s: {} requests, {} bytes served, {} WebSocket sessions (at most {} at a time), {} redis errors",
+            lifetime.uptime.as_secs(), lifetime.requests, lifetime.bytes, lifetime.ws_sessions, lifetime.peak_ws_sessions, lifetime.redis_errors);
+    };
+
+    // Serve requests using our `async serve_req` function.
+    // `serve` takes a closure which returns a type implementing the
+    // `Service` trait. `service_fn` returns a value implementing the
+    // `Service` trait, and accepts a closure which goes from request
+    // to a future of the response. In order to use our `serve_req`
+    // function with Hyper, we have to box it and put it in a compatability
+    // wrapper to go from a futures 0.3 future (the kind returned by
+    // `async fn`) to a futures 0.1 future (the kind used by Hyper).
+    let make_service = move || {
+        let state = state.clone();
+        service_fn(move |req| serve_req(req, state.clone()).boxed().compat())
+    };
+    let shutdown = shutdown.unit_error().boxed().compat();
 
     // Wait for the server to complete serving or exit with an error.
     // If an error occurred, print it to stderr.
-    if let Err(e) = await!(serve_future.compat()) {
+    let result = match Server::from_tcp(listener) {
+        Ok(builder) => {
+            let server = builder
+                .tcp_nodelay(tcp_nodelay)
+                .tcp_keepalive(tcp_keepalive)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown);
+            await!(server.compat())
+        },
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
         error!("server error: {}", e);
         Err(e)
     } else {
@@ -138,7 +971,630 @@ async fn run_server(addr: SocketAddr) -> Result<(), hyper::error::Error> {
 fn main() {
     env_logger::init();
 
-    let addr = "127.0.0.1:8080".parse().unwrap();
+    let path = Config::path();
+    let config = if path.exists() {
+        Config::load(&path).unwrap_or_else(|err| panic!("{}", err))
+    } else {
+        info!("No config file at {:?}, using defaults.", path);
+        Config::default()
+    };
+
+    if std::env::args().skip(1).any(|arg| arg == "--print-config") {
+        let config = toml::Value::try_from(config.redacted()).expect("Failed serializing config");
+        print!("{}", config);
+        return;
+    }
+
+    // Not using tokio::run, since that would wait for the SIGHUP handler,
+    // which never finishes.
+    let mut runtime = tokio::runtime::Runtime::new().expect("Failed to start the runtime");
+    let _ = runtime.block_on(run_server(config).map_err(|e| { error!("{}", e); }).boxed().compat());
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use {
+        super::*,
+        hyper::header::ACCEPT_ENCODING,
+        tokio::prelude::AsyncSink,
+        crate::protocol::OPEN_ROLE,
+    };
+
+    pub fn block_on<T: Send + 'static>(future: impl std::future::Future<Output = T> + Send + 'static) -> T {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(future.unit_error().boxed().compat()).unwrap()
+    }
+
+    // A fresh directory below the system's temp directory.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cellardoor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub fn test_state(config: Config) -> State {
+        State::new(config, Health::default(), Bundle::default())
+    }
+
+    // Routes `req`, returning the status and Allow header of the reply.
+    fn route_allow(config: Config, req: Request<Body>) -> (StatusCode, Option<String>) {
+        let state = Arc::new(test_state(config.clone()));
+        let response = block_on(async move {
+            await!(route(req, Arc::new(config), state, Instant::now())).unwrap_or_else(ServeError::into_response)
+        });
+        let allow = response.headers().get(ALLOW).map(|allow| allow.to_str().unwrap().to_string());
+        (response.status(), allow)
+    }
+
+    fn request(method: &str, path: &str) -> Request<Body> {
+        Request::builder().method(method).uri(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn allowed_methods_depend_on_route() {
+        let mut config = Config::default();
+        assert_eq!(allowed_methods("/index.html", &config), READ_METHODS);
+        assert_eq!(allowed_methods(api::VALIDATE_COMMAND_PATH, &config), COMMAND_METHODS);
+        assert_eq!(allowed_methods("/upload/file.txt", &config), READ_METHODS);
+        config.upload_dir = Some(PathBuf::from("/tmp"));
+        assert_eq!(allowed_methods("/upload/file.txt", &config), UPLOAD_METHODS);
+    }
+
+    #[test]
+    fn options_lists_allowed_methods() {
+        let mut config = Config::default();
+        config.upload_dir = Some(temp_dir("options"));
+        assert_eq!(route_allow(config.clone(), request("OPTIONS", "/index.html")), (StatusCode::NO_CONTENT, Some(READ_METHODS.to_string())));
+        assert_eq!(route_allow(config.clone(), request("OPTIONS", api::VALIDATE_COMMAND_PATH)), (StatusCode::NO_CONTENT, Some(COMMAND_METHODS.to_string())));
+        assert_eq!(route_allow(config, request("OPTIONS", "/upload/file.txt")), (StatusCode::NO_CONTENT, Some(UPLOAD_METHODS.to_string())));
+    }
+
+    #[test]
+    fn refuses_methods_the_route_lacks() {
+        let mut config = Config::default();
+        config.upload_dir = Some(temp_dir("method-not-allowed"));
+        assert_eq!(route_allow(config.clone(), request("DELETE", "/index.html")), (StatusCode::METHOD_NOT_ALLOWED, Some(READ_METHODS.to_string())));
+        assert_eq!(route_allow(config.clone(), request("GET", api::VALIDATE_COMMAND_PATH)), (StatusCode::METHOD_NOT_ALLOWED, Some(COMMAND_METHODS.to_string())));
+        assert_eq!(route_allow(config.clone(), request("POST", "/upload/file.txt")), (StatusCode::METHOD_NOT_ALLOWED, Some(UPLOAD_METHODS.to_string())));
+        assert_eq!(route_allow(config, request("FROB", "/index.html")), (StatusCode::NOT_IMPLEMENTED, None));
+    }
+
+    fn upgrade_headers(connection: &'static str, upgrade: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static(connection));
+        headers.insert(UPGRADE, HeaderValue::from_static(upgrade));
+        headers
+    }
+
+    #[test]
+    fn detects_websocket_upgrades() {
+        assert!(is_websocket_upgrade(&upgrade_headers("Upgrade", "websocket")));
+        assert!(is_websocket_upgrade(&upgrade_headers("keep-alive, Upgrade", "WebSocket")));
+        assert!(is_websocket_upgrade(&upgrade_headers("upgrade", "h2c, websocket/13")));
+        assert!(!is_websocket_upgrade(&upgrade_headers("keep-alive", "websocket")));
+        assert!(!is_websocket_upgrade(&upgrade_headers("Upgrade", "h2c")));
+        assert!(!is_websocket_upgrade(&upgrade_headers("Upgrade-Insecure", "websockets")));
+        assert!(!is_websocket_upgrade(&HeaderMap::new()));
+    }
+
+    fn upgrade_request(version: &'static str) -> Request<Body> {
+        Request::get("/")
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, version)
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn refuses_upgrades_over_the_session_limit() {
+        let state = ws_state();
+        let permit = state.ws_sessions.try_acquire().unwrap();
+        let status = |req: Request<Body>| {
+            let state = state.clone();
+            block_on(async move {
+                let config = state.config.load();
+                await!(route(req, config, state, Instant::now())).unwrap_or_else(ServeError::into_response).status()
+            })
+        };
+        assert_eq!(status(upgrade_request("13")), StatusCode::SERVICE_UNAVAILABLE);
+        // The slot is only asked for once everything else checks out.
+        assert_eq!(status(upgrade_request("8")), StatusCode::BAD_REQUEST);
+        assert_eq!(state.hub.len(), 0);
+        drop(permit);
+        assert!(state.ws_sessions.try_acquire().is_some());
+    }
+
+    fn expecting(expect: Option<&'static str>) -> Request<Body> {
+        let mut req = Request::put("/upload/file.txt");
+        if let Some(expect) = expect {
+            req.header(EXPECT, expect);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn only_expects_100_continue() {
+        assert!(check_expectation(&expecting(None)).is_ok());
+        assert!(check_expectation(&expecting(Some("100-continue"))).is_ok());
+        assert!(check_expectation(&expecting(Some(" 100-Continue "))).is_ok());
+        assert!(match check_expectation(&expecting(Some("200-ok"))) { Err(ServeError::ExpectationFailed) => true, _ => false });
+
+        // Refused before the upload is authorized or its body read.
+        let mut config = Config::default();
+        config.upload_dir = Some(temp_dir("expect"));
+        assert_eq!(route_allow(config, expecting(Some("200-ok"))), (StatusCode::EXPECTATION_FAILED, None));
+    }
+
+    #[test]
+    fn tells_unknown_methods_from_unsupported_ones() {
+        assert!(is_standard_method(&Method::DELETE));
+        assert!(is_standard_method(&Method::PATCH));
+        assert!(!is_standard_method(&Method::from_bytes(b"FROB").unwrap()));
+        assert!(!is_standard_method(&Method::from_bytes(b"PROPFIND").unwrap()));
+
+        let config = static_config("not-implemented", &[("file.txt", "file")]);
+        assert_eq!(route_allow(config.clone(), request("PROPFIND", "/file.txt")), (StatusCode::NOT_IMPLEMENTED, None));
+        assert_eq!(route_allow(config, request("PATCH", "/file.txt")), (StatusCode::METHOD_NOT_ALLOWED, Some(READ_METHODS.to_string())));
+    }
+
+    #[test]
+    fn commands_check_the_session_roles() {
+        let state = test_state(Config::default());
+        let (id, _receiver) = state.hub.register(false);
+        let roles = |roles: &[&str]| roles.iter().map(|role| role.to_string()).collect::<Vec<String>>();
+        let forbidden = ServerMessage::Error { message: String::from("forbidden") };
+
+        assert_eq!(handle_command(ClientMessage::Sessions, &state, id, &roles(&[])), forbidden);
+        assert_eq!(handle_command(ClientMessage::Sessions, &state, id, &roles(&[OPEN_ROLE])), forbidden);
+        assert!(match handle_command(ClientMessage::Sessions, &state, id, &roles(&[ADMIN_ROLE])) {
+            ServerMessage::Sessions { sessions } => sessions.len() == 1,
+            _ => false,
+        });
+        assert_eq!(handle_command(ClientMessage::Open, &state, id, &roles(&[ADMIN_ROLE])), forbidden);
+        assert_ne!(handle_command(ClientMessage::Open, &state, id, &roles(&[OPEN_ROLE])), forbidden);
+        assert_ne!(handle_command(ClientMessage::Status, &state, id, &roles(&[])), forbidden);
+    }
+
+    // A WebSocket connection whose client sends `incoming` and then either
+    // hangs up or goes quiet. Whatever the server sends ends up in `sent`.
+    struct MockConnection {
+        incoming: Vec<OwnedMessage>,
+        hang_up: bool,
+        sent: Arc<Mutex<Vec<OwnedMessage>>>,
+    }
+
+    impl Stream for MockConnection {
+        type Item = OwnedMessage;
+        type Error = WebSocketError;
+
+        fn poll(&mut self) -> Result<Async<Option<OwnedMessage>>, WebSocketError> {
+            if !self.incoming.is_empty() {
+                Ok(Async::Ready(Some(self.incoming.remove(0))))
+            } else if self.hang_up {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    impl Sink for MockConnection {
+        type SinkItem = OwnedMessage;
+        type SinkError = WebSocketError;
+
+        fn start_send(&mut self, message: OwnedMessage) -> Result<AsyncSink<OwnedMessage>, WebSocketError> {
+            self.sent.lock().unwrap().push(message);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Result<Async<()>, WebSocketError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    // Runs a session on a connection that sends `incoming`, returning what
+    // the server sent back. The state allows a single session, so that the
+    // tests can tell whether its slot was given back.
+    fn ws_session(state: Arc<State>, incoming: Vec<OwnedMessage>, hang_up: bool) -> Vec<OwnedMessage> {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let connection = MockConnection { incoming, hang_up, sent: sent.clone() };
+        let permit = state.ws_sessions.try_acquire().expect("session slot is free");
+        block_on(serve_ws(connection, state, Vec::new(), permit));
+        let sent = sent.lock().unwrap().clone();
+        sent
+    }
+
+    fn ws_state() -> Arc<State> {
+        let mut config = Config::default();
+        config.max_ws_sessions = 1;
+        Arc::new(test_state(config))
+    }
+
+    #[test]
+    fn ws_session_ends_on_close() {
+        let state = ws_state();
+        let sent = ws_session(state.clone(), vec![
+            OwnedMessage::Text(String::from(r#"{"version":1,"cmd":"status"}"#)),
+            OwnedMessage::Close(None),
+            OwnedMessage::Text(String::from(r#"{"version":1,"cmd":"status"}"#)),
+        ], false);
+        assert_eq!(sent.len(), 1);
+        assert!(match sent[0] { OwnedMessage::Text(_) => true, _ => false });
+        assert_eq!(state.hub.len(), 0);
+        assert!(state.ws_sessions.try_acquire().is_some());
+    }
+
+    #[test]
+    fn ws_replies_carry_the_correlation_id() {
+        let sent = ws_session(ws_state(), vec![
+            OwnedMessage::Text(String::from(r#"{"version":1,"correlation_id":"c1","cmd":"status"}"#)),
+            OwnedMessage::Text(String::from(r#"{"version":1,"correlation_id":"c2","cmd":"fly"}"#)),
+            OwnedMessage::Text(String::from(r#"{"version":1,"cmd":"status"}"#)),
+        ], true);
+        let replies = sent.iter().map(|message| match message {
+            OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(text).unwrap(),
+            message => panic!("Unexpected message {:?}", message),
+        }).collect::<Vec<_>>();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["correlation_id"], "c1");
+        assert_eq!(replies[1]["correlation_id"], "c2");
+        assert_eq!(replies[1]["cmd"], "error");
+        assert!(replies[2].get("correlation_id").is_none());
+    }
+
+    #[test]
+    fn ws_session_ends_on_hang_up() {
+        let state = ws_state();
+        let sent = ws_session(state.clone(), vec![OwnedMessage::Ping(Vec::new())], true);
+        assert!(sent.is_empty());
+        assert_eq!(state.hub.len(), 0);
+        assert!(state.ws_sessions.try_acquire().is_some());
+    }
+
+    #[test]
+    fn idle_ws_session_is_swept() {
+        let state = ws_state();
+        let sweeper_state = state.clone();
+        let sweeper = std::thread::spawn(move || {
+            while sweeper_state.hub.len() == 0 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            sweeper_state.hub.sweep(Duration::from_secs(0))
+        });
+        let sent = ws_session(state.clone(), Vec::new(), false);
+        assert_eq!(sweeper.join().unwrap(), 1);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].is_close());
+        assert_eq!(state.hub.len(), 0);
+        assert!(state.ws_sessions.try_acquire().is_some());
+    }
+
+    // A state whose authenticator knows the "admin-token" and "member-token"
+    // tokens, and nothing about the tokens in the config, with /private/**
+    // restricted to admins.
+    fn custom_auth_state(name: &str) -> Arc<State> {
+        let mut config = Config::default();
+        config.static_root = temp_dir(name);
+        config.tokens.insert(String::from("config-token"), vec![String::from(ADMIN_ROLE)]);
+        std::fs::create_dir(config.static_root.join("private")).unwrap();
+        std::fs::write(config.static_root.join("private/report.txt"), "report").unwrap();
+        let manifest = config.static_root.join("access.toml");
+        std::fs::write(&manifest, "[[rules]]\npath = \"/private/**\"\nroles = [\"admin\"]\n").unwrap();
+        config.access = access::AccessManifest::load(&manifest).unwrap();
+        let mut authenticator = auth::MockAuthenticator::default();
+        authenticator.tokens.insert(String::from("admin-token"), vec![String::from(ADMIN_ROLE)]);
+        authenticator.tokens.insert(String::from("member-token"), vec![String::from("member")]);
+        let mut state = test_state(config);
+        state.authenticator = Box::new(authenticator);
+        Arc::new(state)
+    }
+
+    fn get_with_token(path: &str, token: Option<&str>) -> Request<Body> {
+        let mut req = Request::get(path);
+        if let Some(token) = token {
+            req.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token).as_str());
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn request_roles_asks_the_authenticator() {
+        let state = custom_auth_state("custom-auth-roles");
+        let roles = |token: Option<&str>| {
+            let (state, req) = (state.clone(), get_with_token("/", token));
+            block_on(async move {
+                let config = state.config.load();
+                await!(request_roles(&req, &config, &state))
+            })
+        };
+        assert_eq!(roles(Some("member-token")), Some(vec![String::from("member")]));
+        assert_eq!(roles(Some("config-token")), None);
+        assert_eq!(roles(None), None);
+    }
+
+    #[test]
+    fn custom_authenticator_guards_static_files() {
+        let state = custom_auth_state("custom-auth-files");
+        let status = |token: Option<&str>| {
+            let (state, req) = (state.clone(), get_with_token("/private/report.txt", token));
+            block_on(async move {
+                let config = state.config.load();
+                await!(route(req, config, state, Instant::now())).unwrap_or_else(ServeError::into_response).status()
+            })
+        };
+        assert_eq!(status(None), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("config-token")), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("member-token")), StatusCode::FORBIDDEN);
+        assert_eq!(status(Some("admin-token")), StatusCode::OK);
+    }
+
+    // A config serving `files` (paths below the root and their contents)
+    // from a fresh static root.
+    fn static_config(name: &str, files: &[(&str, &str)]) -> Config {
+        let mut config = Config::default();
+        config.static_root = temp_dir(name);
+        for (path, contents) in files {
+            let path = config.static_root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        config
+    }
+
+    // Routes `req`, returning the status, headers and body of the reply.
+    fn fetch(config: Config, req: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let state = Arc::new(test_state(config.clone()));
+        block_on(async move {
+            let response = await!(route(req, Arc::new(config), state, Instant::now())).unwrap_or_else(ServeError::into_response);
+            let (parts, body) = response.into_parts();
+            let body = await!(body.concat2().compat()).unwrap();
+            (parts.status, parts.headers, body.to_vec())
+        })
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let config = static_config("precompressed", &[("app.js", "plain"), ("app.js.br", "brotli"), ("app.js.gz", "gzip"), ("style.css", "plain")]);
+        let get = |accept_encoding: &'static str| {
+            let req = Request::get("/app.js").header(ACCEPT_ENCODING, accept_encoding).body(Body::empty()).unwrap();
+            fetch(config.clone(), req)
+        };
+
+        let (status, headers, body) = get("gzip, br");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_ENCODING], "br");
+        assert_eq!(headers[VARY], "Accept-Encoding");
+        assert_eq!(headers[CONTENT_TYPE], "application/javascript");
+        assert_eq!(body, b"brotli");
 
-    tokio::run(run_server(addr).map_err(|e| { error!("{}", e); }).boxed().compat());
+        let (_, headers, body) = get("gzip");
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(body, b"gzip");
+
+        let (_, headers, body) = get("identity");
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(headers[VARY], "Accept-Encoding");
+        assert_eq!(body, b"plain");
+
+        // Without a sibling, the original is served.
+        let req = Request::get("/style.css").header(ACCEPT_ENCODING, "br").body(Body::empty()).unwrap();
+        let (_, headers, body) = fetch(config.clone(), req);
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(body, b"plain");
+    }
+
+    #[test]
+    fn serves_ranges_of_cached_files() {
+        let config = static_config("ranges", &[("file.txt", "0123456789")]);
+        let get = |range: &'static str| fetch(config.clone(), Request::get("/file.txt").header(RANGE, range).body(Body::empty()).unwrap());
+
+        let (status, headers, body) = get("bytes=2-4");
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(headers[ACCEPT_RANGES], "bytes");
+        assert_eq!(body, b"234");
+
+        let (status, _, body) = get("bytes=-3");
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"789");
+
+        let (status, headers, body) = get("bytes=20-");
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[CONTENT_RANGE], "bytes */10");
+        assert!(body.is_empty());
+
+        let (status, _, body) = get("bytes=0-1,4-5");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn serves_everything_if_the_range_is_of_another_version() {
+        let mut config = static_config("if-range", &[("file.txt", "0123456789")]);
+        config.etag = EtagStrategy::Strong;
+        let get = |if_range: &str| {
+            let req = Request::get("/file.txt").header(RANGE, "bytes=2-4").header(IF_RANGE, if_range).body(Body::empty()).unwrap();
+            fetch(config.clone(), req)
+        };
+        let (_, headers, _) = fetch(config.clone(), request("GET", "/file.txt"));
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+
+        let (status, _, body) = get(&etag);
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"234");
+
+        let (status, headers, body) = get("\"0000000000000000000000000000000000000000\"");
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get(CONTENT_RANGE).is_none());
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn sniffs_extensionless_files_if_enabled() {
+        let mut config = static_config("sniff", &[("README", "Read me\n"), ("blob", "\u{0}\u{1}\u{2}")]);
+        let content_type = |config: &Config, path: &str| {
+            let (_, headers, _) = fetch(config.clone(), request("GET", path));
+            headers.get(CONTENT_TYPE).map(|value| value.to_str().unwrap().to_string())
+        };
+        assert_eq!(content_type(&config, "/README"), None);
+        config.sniff_content_type = true;
+        assert_eq!(content_type(&config, "/README"), Some(String::from("text/plain; charset=utf-8")));
+        assert_eq!(content_type(&config, "/blob"), Some(String::from("application/octet-stream")));
+    }
+
+    #[test]
+    fn hides_dotfiles_outside_allowed_prefixes() {
+        assert!(is_dotfile("/.env"));
+        assert!(is_dotfile("/.git/config"));
+        assert!(is_dotfile("/dir/.htpasswd"));
+        assert!(!is_dotfile("/dir/file.txt"));
+        assert!(!is_dotfile("/"));
+
+        let mut config = static_config("dotfiles", &[(".env", "secret"), (".git/config", "secret"), (".well-known/security.txt", "contact")]);
+        let status = |config: &Config, path: &str| fetch(config.clone(), request("GET", path)).0;
+        assert_eq!(status(&config, "/.env"), StatusCode::NOT_FOUND);
+        assert_eq!(status(&config, "/.git/config"), StatusCode::NOT_FOUND);
+        assert_eq!(status(&config, "/.well-known/security.txt"), StatusCode::OK);
+        config.hide_dotfiles = false;
+        assert_eq!(status(&config, "/.env"), StatusCode::OK);
+    }
+
+    #[test]
+    fn serves_the_first_index_file_that_exists() {
+        let mut config = static_config("index-files", &[("docs/index.html", "html"), ("app/index.htm", "htm"), ("app/index.html", "html")]);
+        config.index_files = vec![String::from("index.htm"), String::from("index.html")];
+        std::fs::create_dir(config.static_root.join("empty")).unwrap();
+        let index = |path: &'static str| {
+            let config = config.clone();
+            block_on(async move { await!(index_file(&config, &Bundle::default(), path)) })
+        };
+        assert_eq!(index("/docs/"), "/docs/index.html");
+        assert_eq!(index("/app/"), "/app/index.htm");
+        assert_eq!(index("/empty/"), "/empty/index.htm");
+
+        assert_eq!(fetch(config.clone(), request("GET", "/docs/")).2, b"html");
+        assert_eq!(fetch(config.clone(), request("GET", "/app/")).2, b"htm");
+        assert_eq!(fetch(config, request("GET", "/empty/")).0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn serves_empty_files_with_a_length() {
+        let mut config = static_config("empty-file", &[("empty.txt", ""), ("file.txt", "file")]);
+        config.cache.enabled = false;
+        let (status, headers, body) = fetch(config.clone(), request("GET", "/empty.txt"));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_LENGTH], "0");
+        assert!(body.is_empty());
+
+        // Anything else is streamed.
+        let (_, headers, body) = fetch(config, request("GET", "/file.txt"));
+        assert!(headers.get(CONTENT_LENGTH).is_none());
+        assert_eq!(body, b"file");
+    }
+
+    fn conditional_get(etag: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn serves_data_unless_not_modified() {
+        let state = test_state(Config::default());
+        let serve = |headers: HeaderMap| serve_data(&state, Response::builder(), Bytes::from_static(b"data"), "\"tag\"", &headers, Instant::now()).unwrap();
+        assert_eq!(serve(conditional_get(None)).status(), StatusCode::OK);
+        assert_eq!(serve(conditional_get(Some("\"other\""))).status(), StatusCode::OK);
+        let response = serve(conditional_get(Some("\"other\", W/\"tag\"")));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], "\"tag\"");
+        assert_eq!(serve(conditional_get(Some("*"))).status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn serves_file_unless_not_modified() {
+        let mut config = Config::default();
+        config.static_root = temp_dir("not-modified");
+        config.cache.enabled = false;
+        std::fs::write(config.static_root.join("file.txt"), "file").unwrap();
+        let path = config.static_root.join("file.txt");
+        let state = Arc::new(test_state(config.clone()));
+        let config = Arc::new(config);
+        let serve = move |headers: HeaderMap| {
+            let (state, config, path) = (state.clone(), config.clone(), path.clone());
+            block_on(async move {
+                await!(serve_file(state, config, path, None, None, &headers, Instant::now())).unwrap()
+            })
+        };
+        let response = serve(conditional_get(None));
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        assert_eq!(serve(conditional_get(Some(&etag))).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(serve(conditional_get(Some("\"other\""))).status(), StatusCode::OK);
+    }
+
+    fn canonical(config: &Config, path: &'static str, raw_path: &'static str) -> Option<String> {
+        let config = config.clone();
+        block_on(async move {
+            await!(canonical_path(&config, &Bundle::default(), path, raw_path))
+        })
+    }
+
+    #[test]
+    fn redirects_to_canonical_path() {
+        let mut config = Config::default();
+        config.static_root = temp_dir("canonical");
+        std::fs::create_dir(config.static_root.join("dir")).unwrap();
+        std::fs::write(config.static_root.join("file.txt"), "file").unwrap();
+
+        assert_eq!(canonical(&config, "/dir", "/dir"), Some("/dir/".to_string()));
+        assert_eq!(canonical(&config, "/dir", "/d%69r"), Some("/d%69r/".to_string()));
+        assert_eq!(canonical(&config, "/file.txt/", "/file.txt/"), Some("/file.txt".to_string()));
+        assert_eq!(canonical(&config, "/dir/", "/dir/"), None);
+        assert_eq!(canonical(&config, "/file.txt", "/file.txt"), None);
+        assert_eq!(canonical(&config, "/missing", "/missing"), None);
+        assert_eq!(canonical(&config, "/", "/"), None);
+    }
+
+    #[test]
+    fn redirect_stays_on_this_host() {
+        assert_eq!(redirect_location("//evil.example/dir", true), "/evil.example/dir/");
+        assert_eq!(redirect_location("///evil.example/file/", false), "/evil.example/file");
+        assert_eq!(redirect_location("/dir", true), "/dir/");
+    }
+
+    // Requests per second for a small asset, read from disk every time or
+    // served from the cache.
+    fn bench_serving(b: &mut test::Bencher, name: &str, cache: bool) {
+        let mut config = static_config(name, &[("app.js", "console.log(\"cellardoor\");\n")]);
+        config.cache.enabled = cache;
+        let state = Arc::new(test_state(config));
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        b.iter(|| {
+            let state = state.clone();
+            let body = async move {
+                let response = await!(serve_req(request("GET", "/app.js"), state)).unwrap();
+                await!(response.into_body().concat2().compat()).unwrap()
+            };
+            runtime.block_on(body.unit_error().boxed().compat()).unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_requests_without_cache(b: &mut test::Bencher) {
+        bench_serving(b, "bench-uncached", false);
+    }
+
+    #[bench]
+    fn bench_requests_with_cache(b: &mut test::Bencher) {
+        bench_serving(b, "bench-cached", true);
+    }
 }
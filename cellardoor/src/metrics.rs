@@ -0,0 +1,174 @@
+use {
+    hyper::{Body, Response, header::CONTENT_TYPE},
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{
+            Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    },
+    websocket::message::OwnedMessage,
+    crate::error::ServeError,
+};
+
+// Latency histograms for static file responses, exposed in the Prometheus
+// text format. Unlike the stats, these are never reset; Prometheus expects
+// cumulative values.
+
+pub const METRICS_PATH: &'static str = "/metrics";
+
+struct Buckets {
+    // Cumulative, one per upper bound plus the final +Inf.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+pub struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Mutex<Buckets>,
+}
+
+impl Histogram {
+    // `bounds` are the bucket upper bounds in seconds, in ascending order.
+    fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            buckets: Mutex::new(Buckets {
+                counts: vec![0; bounds.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let first = self.bounds.iter().position(|bound| seconds <= *bound).unwrap_or(self.bounds.len());
+        let mut buckets = self.buckets.lock().unwrap();
+        for count in &mut buckets.counts[first..] {
+            *count += 1;
+        }
+        buckets.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let buckets = self.buckets.lock().unwrap();
+        for (bound, count) in self.bounds.iter().zip(&buckets.counts) {
+            writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count).unwrap();
+        }
+        let total = buckets.counts[self.bounds.len()];
+        writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, total).unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, buckets.sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, total).unwrap();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Received => "received",
+            Direction::Sent => "sent",
+        }
+    }
+}
+
+fn message_kind(message: &OwnedMessage) -> &'static str {
+    match message {
+        OwnedMessage::Text(_) => "text",
+        OwnedMessage::Binary(_) => "binary",
+        OwnedMessage::Ping(_) => "ping",
+        OwnedMessage::Pong(_) => "pong",
+        OwnedMessage::Close(_) => "close",
+    }
+}
+
+// Time from receiving the request to the first and to the last byte of the
+// body, separately for files served from the cache and from disk, how many
+// Redis operations exceeded `slow_redis_ms`, the WebSocket traffic by frame
+// type and command, and how many upgrades failed after the 101 was sent.
+pub struct Metrics {
+    pub cache_first_byte: Histogram,
+    pub cache_complete: Histogram,
+    pub disk_first_byte: Histogram,
+    pub disk_complete: Histogram,
+    slow_redis_ops: AtomicUsize,
+    ws_messages: Mutex<BTreeMap<(Direction, &'static str), u64>>,
+    ws_commands: Mutex<BTreeMap<&'static str, u64>>,
+    ws_failed_upgrades: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new(bounds: &[f64]) -> Self {
+        Metrics {
+            cache_first_byte: Histogram::new(bounds),
+            cache_complete: Histogram::new(bounds),
+            disk_first_byte: Histogram::new(bounds),
+            disk_complete: Histogram::new(bounds),
+            slow_redis_ops: AtomicUsize::new(0),
+            ws_messages: Mutex::new(BTreeMap::new()),
+            ws_commands: Mutex::new(BTreeMap::new()),
+            ws_failed_upgrades: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn slow_redis_op(&self) {
+        self.slow_redis_ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ws_message(&self, direction: Direction, message: &OwnedMessage) {
+        *self.ws_messages.lock().unwrap().entry((direction, message_kind(message))).or_insert(0) += 1;
+    }
+
+    // `command` is the command's name, or "invalid" for frames that didn't
+    // parse.
+    pub fn ws_command(&self, command: &'static str) {
+        *self.ws_commands.lock().unwrap().entry(command).or_insert(0) += 1;
+    }
+
+    pub fn ws_failed_upgrade(&self) {
+        self.ws_failed_upgrades.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, cache, disk) in &[
+            ("cellardoor_file_first_byte_seconds", "Time from request to the first byte of a static file.", &self.cache_first_byte, &self.disk_first_byte),
+            ("cellardoor_file_complete_seconds", "Time from request to the last byte of a static file.", &self.cache_complete, &self.disk_complete),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            cache.render(&mut out, name, "source=\"cache\"");
+            disk.render(&mut out, name, "source=\"disk\"");
+        }
+        writeln!(out, "# HELP cellardoor_redis_slow_operations_total Redis operations slower than slow_redis_ms.").unwrap();
+        writeln!(out, "# TYPE cellardoor_redis_slow_operations_total counter").unwrap();
+        writeln!(out, "cellardoor_redis_slow_operations_total {}", self.slow_redis_ops.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "# HELP cellardoor_ws_messages_total WebSocket frames by direction and type.").unwrap();
+        writeln!(out, "# TYPE cellardoor_ws_messages_total counter").unwrap();
+        for ((direction, kind), count) in self.ws_messages.lock().unwrap().iter() {
+            writeln!(out, "cellardoor_ws_messages_total{{direction=\"{}\",type=\"{}\"}} {}", direction.name(), kind, count).unwrap();
+        }
+        writeln!(out, "# HELP cellardoor_ws_commands_total WebSocket commands received, by command.").unwrap();
+        writeln!(out, "# TYPE cellardoor_ws_commands_total counter").unwrap();
+        for (command, count) in self.ws_commands.lock().unwrap().iter() {
+            writeln!(out, "cellardoor_ws_commands_total{{cmd=\"{}\"}} {}", command, count).unwrap();
+        }
+        writeln!(out, "# HELP cellardoor_ws_failed_upgrades_total WebSocket upgrades that failed after 101 Switching Protocols was sent.").unwrap();
+        writeln!(out, "# TYPE cellardoor_ws_failed_upgrades_total counter").unwrap();
+        writeln!(out, "cellardoor_ws_failed_upgrades_total {}", self.ws_failed_upgrades.load(Ordering::Relaxed)).unwrap();
+        out
+    }
+
+    pub fn serve_metrics(&self) -> Result<Response<Body>, ServeError> {
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(self.render()))?)
+    }
+}
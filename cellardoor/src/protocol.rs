@@ -0,0 +1,398 @@
+use {
+    serde::{Deserialize, Serialize},
+    std::fmt,
+};
+
+// The door command protocol spoken over the WebSocket. Every frame is a JSON
+// object carrying the protocol version and a `cmd` tag naming the message,
+// with the message's fields next to it, e.g.
+//
+//     {"version": 1, "cmd": "hold_open", "enabled": true}
+//
+// A command may carry a `correlation_id` string, which is copied into the
+// reply to it (including the error reply to a command that couldn't be
+// parsed), so that clients can match replies to commands without relying on
+// their order. Replies to commands without one, and notifications the server
+// sends on its own, have none. (It isn't called `id`, because enroll already
+// has a field of that name.)
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// Roles (granted by the session's bearer token) that commands can require.
+// Commands without a role, like status, are open to every session, so a
+// token without roles makes for a read-only dashboard.
+pub const OPEN_ROLE: &'static str = "open";
+pub const ADMIN_ROLE: &'static str = "admin";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    // Only used for signed commands, see signing.rs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+// Commands sent by the dashboard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Open,
+    Status,
+    HoldOpen { enabled: bool },
+    Enroll { id: String },
+    // Admin only: list the connected sessions, and disconnect one of them.
+    Sessions,
+    Evict { session: usize },
+}
+
+// Replies and notifications sent by the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Ok,
+    Status { locked: bool, hold_open: bool },
+    Sessions { sessions: Vec<SessionInfo> },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub admin: bool,
+    // Seconds since the session was last heard from.
+    pub idle: u64,
+}
+
+impl ClientMessage {
+    // The `cmd` tag, for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientMessage::Open => "open",
+            ClientMessage::Status => "status",
+            ClientMessage::HoldOpen { .. } => "hold_open",
+            ClientMessage::Enroll { .. } => "enroll",
+            ClientMessage::Sessions => "sessions",
+            ClientMessage::Evict { .. } => "evict",
+        }
+    }
+
+    // The role a session needs to send this command, if any.
+    pub fn role(&self) -> Option<&'static str> {
+        client_message_info(self).role
+    }
+}
+
+impl<T> Envelope<T> {
+    pub fn new(message: T) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            correlation_id: None,
+            timestamp: None,
+            nonce: None,
+            signature: None,
+            message,
+        }
+    }
+
+    pub fn reply_to(correlation_id: Option<String>, message: T) -> Self {
+        Envelope {
+            correlation_id,
+            ..Envelope::new(message)
+        }
+    }
+}
+
+// Why a frame couldn't be parsed. Line and column point into the frame; they
+// are 0 if the problem isn't tied to a position, like a version mismatch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{} at line {} column {}", self.message, self.line, self.column)
+        } else {
+            write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(err: serde_json::Error) -> Self {
+        // serde_json appends the position to its messages, which we report
+        // separately.
+        let message = err.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(index) if err.line() > 0 => message[..index].to_string(),
+            _ => message,
+        };
+        ParseError {
+            message,
+            line: err.line(),
+            column: err.column(),
+        }
+    }
+}
+
+// The result of a dry run through the parser, as returned to integrators.
+#[derive(Debug, Serialize)]
+pub struct Validation {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<ClientMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ParseError>,
+}
+
+pub fn parse_envelope(text: &str) -> Result<Envelope<ClientMessage>, ParseError> {
+    let envelope: Envelope<ClientMessage> = serde_json::from_str(text)?;
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(ParseError {
+            message: format!("Unsupported protocol version {}", envelope.version),
+            line: 0,
+            column: 0,
+        });
+    }
+    Ok(envelope)
+}
+
+pub fn parse(text: &str) -> Result<ClientMessage, ParseError> {
+    parse_envelope(text).map(|envelope| envelope.message)
+}
+
+// The correlation id of a frame that didn't parse as a command, if it is at
+// least a JSON object with one.
+pub fn correlation_id(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    value.get("correlation_id")?.as_str().map(String::from)
+}
+
+pub fn validate(text: &str) -> Validation {
+    match parse(text) {
+        Ok(command) => Validation { ok: true, command: Some(command), error: None },
+        Err(error) => Validation { ok: false, command: None, error: Some(error) },
+    }
+}
+
+// Hand-maintained description of the messages above, served on /api. The
+// matches are exhaustive, so a new variant doesn't compile until it has been
+// described here too; it also has to be added to the example lists.
+struct MessageInfo {
+    fields: &'static [(&'static str, &'static str)],
+    description: &'static str,
+    role: Option<&'static str>,
+}
+
+fn client_message_info(message: &ClientMessage) -> MessageInfo {
+    match message {
+        ClientMessage::Open => MessageInfo { fields: &[], description: "Open the door once.", role: Some(OPEN_ROLE) },
+        ClientMessage::Status => MessageInfo { fields: &[], description: "Ask for the door status.", role: None },
+        ClientMessage::HoldOpen { .. } => MessageInfo { fields: &[("enabled", "boolean")], description: "Keep the door open, or stop doing so.", role: Some(OPEN_ROLE) },
+        ClientMessage::Enroll { .. } => MessageInfo { fields: &[("id", "string")], description: "Enroll an iButton.", role: Some(ADMIN_ROLE) },
+        ClientMessage::Sessions => MessageInfo { fields: &[], description: "List the connected WebSocket sessions.", role: Some(ADMIN_ROLE) },
+        ClientMessage::Evict { .. } => MessageInfo { fields: &[("session", "integer")], description: "Disconnect a WebSocket session.", role: Some(ADMIN_ROLE) },
+    }
+}
+
+fn server_message_info(message: &ServerMessage) -> MessageInfo {
+    match message {
+        ServerMessage::Ok => MessageInfo { fields: &[], description: "The command succeeded.", role: None },
+        ServerMessage::Status { .. } => MessageInfo { fields: &[("locked", "boolean"), ("hold_open", "boolean")], description: "The door status.", role: None },
+        ServerMessage::Sessions { .. } => MessageInfo { fields: &[("sessions", "array of {id: integer, admin: boolean, idle: integer}")], description: "The connected WebSocket sessions.", role: None },
+        ServerMessage::Error { .. } => MessageInfo { fields: &[("message", "string")], description: "The command failed.", role: None },
+    }
+}
+
+fn describe_message<T: Serialize>(message: &T, info: MessageInfo) -> serde_json::Value {
+    let cmd = serde_json::to_value(message).ok()
+        .and_then(|value| value.get("cmd").cloned())
+        .unwrap_or(serde_json::Value::Null);
+    let fields = info.fields.iter()
+        .map(|(name, kind)| (name.to_string(), serde_json::Value::from(*kind)))
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::json!({
+        "cmd": cmd,
+        "description": info.description,
+        "fields": fields,
+        "role": info.role,
+    })
+}
+
+pub fn describe() -> serde_json::Value {
+    let client = [
+        ClientMessage::Open,
+        ClientMessage::Status,
+        ClientMessage::HoldOpen { enabled: false },
+        ClientMessage::Enroll { id: String::new() },
+        ClientMessage::Sessions,
+        ClientMessage::Evict { session: 0 },
+    ];
+    let server = [
+        ServerMessage::Ok,
+        ServerMessage::Status { locked: false, hold_open: false },
+        ServerMessage::Sessions { sessions: Vec::new() },
+        ServerMessage::Error { message: String::new() },
+    ];
+    serde_json::json!({
+        "version": PROTOCOL_VERSION,
+        "correlation_id": "Optional string on any client message, copied into the reply to it.",
+        "signing": "If the server requires signed commands, client messages also carry timestamp (Unix seconds), nonce (string) and signature (hex HMAC-SHA256 of the compact frame without the signature).",
+        "client": client.iter().map(|message| describe_message(message, client_message_info(message))).collect::<Vec<_>>(),
+        "server": server.iter().map(|message| describe_message(message, server_message_info(message))).collect::<Vec<_>>(),
+    })
+}
+
+pub fn encode(message: ServerMessage, correlation_id: Option<String>) -> String {
+    serde_json::to_string(&Envelope::reply_to(correlation_id, message)).expect("Failed serializing server message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(r#"{"version": 1, "cmd": "open"}"#), Ok(ClientMessage::Open));
+        assert_eq!(parse(r#"{"version": 1, "cmd": "hold_open", "enabled": true}"#), Ok(ClientMessage::HoldOpen { enabled: true }));
+        assert_eq!(parse(r#"{"cmd": "enroll", "id": "01ab", "version": 1}"#), Ok(ClientMessage::Enroll { id: String::from("01ab") }));
+        assert_eq!(parse(r#"{"version": 1, "cmd": "evict", "session": 3}"#), Ok(ClientMessage::Evict { session: 3 }));
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        let err = parse(r#"{"version": 2, "cmd": "open"}"#).unwrap_err();
+        assert_eq!(err.message, "Unsupported protocol version 2");
+        assert_eq!((err.line, err.column), (0, 0));
+        assert_eq!(err.to_string(), "Unsupported protocol version 2");
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let err = parse("{\"version\": 1,\n\"cmd\": \"fly\"}").unwrap_err();
+        assert!(err.message.starts_with("unknown variant `fly`"), "{}", err.message);
+        assert!(!err.message.contains(" at line "));
+        assert_eq!(err.line, 2);
+        assert_eq!(err.to_string(), format!("{} at line 2 column {}", err.message, err.column));
+
+        assert!(parse(r#"{"version": 1, "cmd": "hold_open"}"#).is_err());
+        assert!(parse("not json").is_err());
+    }
+
+    #[test]
+    fn encodes_replies() {
+        assert_eq!(encode(ServerMessage::Ok, None), r#"{"version":1,"cmd":"ok"}"#);
+        assert_eq!(
+            encode(ServerMessage::Status { locked: true, hold_open: false }, None),
+            r#"{"version":1,"cmd":"status","locked":true,"hold_open":false}"#
+        );
+    }
+
+    // Serializes `value` and reads it back.
+    fn round_trip<T: Serialize + serde::de::DeserializeOwned + PartialEq + fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{}", json);
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        round_trip(ClientMessage::Open);
+        round_trip(ClientMessage::Status);
+        round_trip(ClientMessage::HoldOpen { enabled: true });
+        round_trip(ClientMessage::Enroll { id: String::from("01ab") });
+        round_trip(ClientMessage::Sessions);
+        round_trip(ClientMessage::Evict { session: 3 });
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        round_trip(ServerMessage::Ok);
+        round_trip(ServerMessage::Status { locked: true, hold_open: false });
+        round_trip(ServerMessage::Sessions { sessions: vec![SessionInfo { id: 1, admin: true, idle: 30 }] });
+        round_trip(ServerMessage::Error { message: String::from("Unknown session") });
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        round_trip(Envelope {
+            version: PROTOCOL_VERSION,
+            correlation_id: None,
+            timestamp: None,
+            nonce: None,
+            signature: None,
+            message: ClientMessage::HoldOpen { enabled: false },
+        });
+        round_trip(Envelope {
+            version: PROTOCOL_VERSION,
+            correlation_id: Some(String::from("c1")),
+            timestamp: Some(1_560_000_000),
+            nonce: Some(String::from("n1")),
+            signature: Some(String::from("00ff")),
+            message: ClientMessage::Enroll { id: String::from("01ab") },
+        });
+        round_trip(Envelope::reply_to(Some(String::from("c2")), ServerMessage::Status { locked: false, hold_open: true }));
+    }
+
+    #[test]
+    fn echoes_correlation_ids() {
+        let envelope = parse_envelope(r#"{"version": 1, "correlation_id": "c1", "cmd": "status"}"#).unwrap();
+        assert_eq!(envelope.correlation_id, Some(String::from("c1")));
+        assert_eq!(envelope.message, ClientMessage::Status);
+        assert_eq!(encode(ServerMessage::Ok, envelope.correlation_id), r#"{"version":1,"correlation_id":"c1","cmd":"ok"}"#);
+        assert_eq!(parse_envelope(r#"{"version": 1, "cmd": "status"}"#).unwrap().correlation_id, None);
+    }
+
+    #[test]
+    fn finds_correlation_ids_of_bad_frames() {
+        assert_eq!(correlation_id(r#"{"version": 1, "correlation_id": "c2", "cmd": "fly"}"#), Some(String::from("c2")));
+        assert_eq!(correlation_id(r#"{"version": 1, "correlation_id": 2, "cmd": "fly"}"#), None);
+        assert_eq!(correlation_id(r#"["correlation_id"]"#), None);
+        assert_eq!(correlation_id("not json"), None);
+    }
+
+    #[test]
+    fn commands_require_roles() {
+        assert_eq!(ClientMessage::Status.role(), None);
+        assert_eq!(ClientMessage::Open.role(), Some(OPEN_ROLE));
+        assert_eq!(ClientMessage::HoldOpen { enabled: true }.role(), Some(OPEN_ROLE));
+        assert_eq!(ClientMessage::Enroll { id: String::new() }.role(), Some(ADMIN_ROLE));
+        assert_eq!(ClientMessage::Sessions.role(), Some(ADMIN_ROLE));
+        assert_eq!(ClientMessage::Evict { session: 0 }.role(), Some(ADMIN_ROLE));
+        assert_eq!(describe()["client"][4]["role"], ADMIN_ROLE);
+        assert!(describe()["client"][1]["role"].is_null());
+    }
+
+    #[test]
+    fn validates_without_running() {
+        let validation = validate(r#"{"version": 1, "cmd": "status"}"#);
+        assert!(validation.ok);
+        assert_eq!(validation.command, Some(ClientMessage::Status));
+        assert!(validation.error.is_none());
+
+        let validation = validate(r#"{"version": 1}"#);
+        assert!(!validation.ok);
+        assert!(validation.command.is_none());
+        assert!(validation.error.is_some());
+    }
+
+    #[test]
+    fn describes_every_message() {
+        let description = describe();
+        assert_eq!(description["version"], PROTOCOL_VERSION);
+        let client = description["client"].as_array().unwrap();
+        assert_eq!(client.len(), 6);
+        assert_eq!(client[2]["cmd"], "hold_open");
+        assert_eq!(client[2]["fields"]["enabled"], "boolean");
+        assert_eq!(description["server"].as_array().unwrap().len(), 4);
+    }
+}
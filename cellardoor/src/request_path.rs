@@ -0,0 +1,127 @@
+use {
+    std::path::{Component, Path, PathBuf},
+    crate::error::ServeError,
+};
+
+// Turns the path of a request URI into the path of a file below the static
+// root. The URI may carry percent-encoded bytes, so it's decoded before it's
+// checked against the access rules or touched on disk; anything that doesn't
+// decode to a plain path is refused.
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+pub fn decode(path: &str) -> Result<String, ServeError> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let high = bytes.get(index + 1).cloned().and_then(hex_value);
+            let low = bytes.get(index + 2).cloned().and_then(hex_value);
+            match (high, low) {
+                (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                _ => return Err(ServeError::BadRequest("Invalid percent-encoding in path")),
+            }
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| ServeError::BadRequest("Path is not valid UTF-8"))?;
+    if decoded.contains('\0') {
+        return Err(ServeError::BadRequest("Path contains a null byte"));
+    }
+    // Backslashes are separators on some platforms, so they could smuggle in
+    // a `..` that the check below doesn't see.
+    if !decoded.starts_with('/') || decoded.contains('\\') {
+        return Err(ServeError::BadRequest("Invalid path"));
+    }
+    // Empty segments are refused as well, since `//etc/passwd` (or
+    // `/%2Fetc/passwd`) would be an absolute path once the leading slash is
+    // cut off. Only the last segment may be empty, for a trailing slash.
+    let segments: Vec<&str> = decoded[1..].split('/').collect();
+    let last = segments.len() - 1;
+    if segments.iter().enumerate().any(|(index, segment)| (segment.is_empty() && index != last) || *segment == "." || *segment == "..") {
+        return Err(ServeError::BadRequest("Invalid path"));
+    }
+    Ok(decoded)
+}
+
+// The file below `root` that the decoded request `path` names. Built one
+// component at a time, and only from plain names, so that whatever slipped
+// past `decode` can't lead out of `root`.
+pub fn join(root: &Path, path: &str) -> Option<PathBuf> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut joined = root.to_path_buf();
+    for component in Path::new(&path[1..]).components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            _ => return None,
+        }
+    }
+    Some(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(decode("/a%20b/c%C3%A4").unwrap(), "/a b/c\u{e4}");
+        assert_eq!(decode("/").unwrap(), "/");
+        assert_eq!(decode("/dir/").unwrap(), "/dir/");
+    }
+
+    #[test]
+    fn refuses_invalid_escapes() {
+        assert!(decode("/a%zz").is_err());
+        assert!(decode("/a%2").is_err());
+        assert!(decode("/a%").is_err());
+        assert!(decode("/%ff").is_err());
+    }
+
+    #[test]
+    fn refuses_null_bytes() {
+        assert!(decode("/a%00b").is_err());
+        assert!(decode("/index.html%00.png").is_err());
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        assert!(decode("/../etc/passwd").is_err());
+        assert!(decode("/a/%2E%2E/b").is_err());
+        assert!(decode("/a%5C..%5Cb").is_err());
+        assert!(decode("relative").is_err());
+    }
+
+    #[test]
+    fn refuses_empty_and_dot_segments() {
+        assert!(decode("//etc/passwd").is_err());
+        assert!(decode("/%2Fetc/passwd").is_err());
+        assert!(decode("/a//b").is_err());
+        assert!(decode("/./private/x").is_err());
+        assert!(decode("/a/.").is_err());
+    }
+
+    #[test]
+    fn joins_plain_names_only() {
+        let root = Path::new("/srv/www");
+        assert_eq!(join(root, "/a/b.html"), Some(PathBuf::from("/srv/www/a/b.html")));
+        assert_eq!(join(root, "/dir/"), Some(PathBuf::from("/srv/www/dir")));
+        assert_eq!(join(root, "/"), Some(PathBuf::from("/srv/www")));
+        assert_eq!(join(root, "//etc/passwd"), None);
+        assert_eq!(join(root, "/../etc/passwd"), None);
+        assert_eq!(join(root, "etc/passwd"), None);
+    }
+}
@@ -0,0 +1,77 @@
+use {
+    futures::channel::oneshot,
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    },
+};
+
+// A minimal async semaphore: `acquire` waits until one of a fixed number of
+// permits is free. Waiters are served in FIFO order; a permit is returned
+// when it's dropped.
+
+struct Inner {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+pub struct Semaphore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+pub struct Permit {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            inner: Arc::new(Mutex::new(Inner {
+                available: permits,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    // Takes a permit if one is free right away.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.available > 0 {
+            inner.available -= 1;
+            Some(Permit { inner: self.inner.clone() })
+        } else {
+            None
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit {
+        let receiver = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.available > 0 {
+                inner.available -= 1;
+                return Permit { inner: self.inner.clone() };
+            }
+            let (sender, receiver) = oneshot::channel();
+            inner.waiters.push_back(sender);
+            receiver
+        };
+        // The sender is only dropped after handing over a permit.
+        await!(receiver).expect("Semaphore waiter was dropped")
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(waiter) = inner.waiters.pop_front() {
+            // If the waiter gave up in the meantime, the permit comes back
+            // and goes to the next one in line. It's forgotten rather than
+            // dropped, since dropping it would end up back here.
+            match waiter.send(Permit { inner: self.inner.clone() }) {
+                Ok(()) => return,
+                Err(permit) => std::mem::forget(permit),
+            }
+        }
+        inner.available += 1;
+    }
+}
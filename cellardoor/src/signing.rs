@@ -0,0 +1,153 @@
+use {
+    hmac::{Hmac, Mac},
+    sha2::Sha256,
+    std::{
+        collections::HashMap,
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    crate::protocol::{ClientMessage, Envelope},
+};
+
+// Optional signing of WebSocket commands, on top of the token checked when
+// the session is opened. With a `command_secret` configured, every command
+// has to carry a Unix `timestamp`, a `nonce` and a `signature`: the
+// hex-encoded HMAC-SHA256, keyed with the secret, of the command frame
+// serialized as compact JSON without the signature, with the fields in the
+// order version, correlation_id (if any), timestamp, nonce, cmd and the
+// command's own fields, e.g.
+//
+//     {"version":1,"timestamp":1560000000,"nonce":"a1b2","cmd":"open"}
+//
+// Commands whose timestamp is more than `command_max_age` seconds off, or
+// whose nonce was already used within that window, are refused, so that a
+// recorded command can't be sent again.
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Nonces seen recently, with the timestamps they came with.
+#[derive(Default)]
+pub struct NonceCache {
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
+
+// Checks the signature and freshness of a command. The error says why it was
+// refused, for the reply to the client.
+pub fn verify(envelope: &Envelope<ClientMessage>, secret: &str, max_age: u64, nonces: &NonceCache) -> Result<(), &'static str> {
+    let (timestamp, nonce, signature) = match (envelope.timestamp, &envelope.nonce, &envelope.signature) {
+        (Some(timestamp), Some(nonce), Some(signature)) => (timestamp, nonce, signature),
+        _ => return Err("Command is not signed"),
+    };
+    let signed = Envelope { signature: None, ..envelope.clone() };
+    let canonical = serde_json::to_string(&signed).map_err(|_| "Command is not signed")?;
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.input(canonical.as_bytes());
+    let signature = decode_hex(signature).ok_or("Bad signature")?;
+    mac.verify(&signature).map_err(|_| "Bad signature")?;
+
+    // Only checked once the signature is known to be good, so that forged
+    // commands can't fill the cache.
+    let now = unix_time();
+    if timestamp.saturating_add(max_age) < now || timestamp > now.saturating_add(max_age) {
+        return Err("Stale command");
+    }
+    let mut seen = nonces.seen.lock().unwrap();
+    seen.retain(|_, seen_at| *seen_at + max_age >= now);
+    if seen.insert(nonce.clone(), timestamp).is_some() {
+        return Err("Replayed command");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::protocol::parse_envelope,
+    };
+
+    const SECRET: &'static str = "sesame";
+
+    // A status command signed with `secret`.
+    fn signed(timestamp: u64, nonce: &str, secret: &str) -> Envelope<ClientMessage> {
+        let mut envelope = Envelope::new(ClientMessage::Status);
+        envelope.timestamp = Some(timestamp);
+        envelope.nonce = Some(nonce.to_string());
+        let mut mac = HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(serde_json::to_string(&envelope).unwrap().as_bytes());
+        let signature = mac.result().code().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        envelope.signature = Some(signature);
+        envelope
+    }
+
+    #[test]
+    fn signs_the_documented_frame() {
+        let envelope = parse_envelope(r#"{"version":1,"timestamp":1560000000,"nonce":"a1b2","cmd":"open"}"#).unwrap();
+        assert_eq!(serde_json::to_string(&envelope).unwrap(), r#"{"version":1,"timestamp":1560000000,"nonce":"a1b2","cmd":"open"}"#);
+    }
+
+    #[test]
+    fn accepts_signed_commands_once() {
+        let nonces = NonceCache::default();
+        let envelope = signed(unix_time(), "n1", SECRET);
+        assert_eq!(verify(&envelope, SECRET, 30, &nonces), Ok(()));
+        assert_eq!(verify(&envelope, SECRET, 30, &nonces), Err("Replayed command"));
+        assert_eq!(verify(&signed(unix_time(), "n2", SECRET), SECRET, 30, &nonces), Ok(()));
+    }
+
+    #[test]
+    fn refuses_unsigned_and_forged_commands() {
+        let nonces = NonceCache::default();
+        assert_eq!(verify(&Envelope::new(ClientMessage::Status), SECRET, 30, &nonces), Err("Command is not signed"));
+        assert_eq!(verify(&signed(unix_time(), "n1", "guess"), SECRET, 30, &nonces), Err("Bad signature"));
+
+        let mut tampered = signed(unix_time(), "n2", SECRET);
+        tampered.message = ClientMessage::Open;
+        assert_eq!(verify(&tampered, SECRET, 30, &nonces), Err("Bad signature"));
+
+        let mut garbled = signed(unix_time(), "n3", SECRET);
+        garbled.signature = Some(String::from("not hex"));
+        assert_eq!(verify(&garbled, SECRET, 30, &nonces), Err("Bad signature"));
+        // Forged commands don't use up nonces.
+        assert!(nonces.seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_stale_commands() {
+        let nonces = NonceCache::default();
+        assert_eq!(verify(&signed(unix_time() - 60, "n1", SECRET), SECRET, 30, &nonces), Err("Stale command"));
+        assert_eq!(verify(&signed(unix_time() + 60, "n2", SECRET), SECRET, 30, &nonces), Err("Stale command"));
+    }
+
+    #[test]
+    fn forgets_nonces_outside_the_window() {
+        let nonces = NonceCache::default();
+        nonces.seen.lock().unwrap().insert(String::from("old"), unix_time() - 60);
+        assert_eq!(verify(&signed(unix_time(), "n1", SECRET), SECRET, 30, &nonces), Ok(()));
+        let seen = nonces.seen.lock().unwrap();
+        assert!(!seen.contains_key("old"));
+        assert!(seen.contains_key("n1"));
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+// Activity counters for the periodic summary in the log. They are reset each
+// time a summary is taken. Totals over the whole lifetime of the process are
+// kept next to them, for the summary logged on shutdown.
+
+pub struct Stats {
+    requests: AtomicUsize,
+    bytes: AtomicUsize,
+    redis_errors: AtomicUsize,
+    started: Instant,
+    total_requests: AtomicUsize,
+    total_bytes: AtomicUsize,
+    total_redis_errors: AtomicUsize,
+    ws_sessions: AtomicUsize,
+    peak_ws_sessions: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub struct Summary {
+    pub requests: usize,
+    pub bytes: usize,
+    pub redis_errors: usize,
+}
+
+#[derive(Debug)]
+pub struct Lifetime {
+    pub uptime: Duration,
+    pub requests: usize,
+    pub bytes: usize,
+    pub redis_errors: usize,
+    pub ws_sessions: usize,
+    pub peak_ws_sessions: usize,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            requests: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            redis_errors: AtomicUsize::new(0),
+            started: Instant::now(),
+            total_requests: AtomicUsize::new(0),
+            total_bytes: AtomicUsize::new(0),
+            total_redis_errors: AtomicUsize::new(0),
+            ws_sessions: AtomicUsize::new(0),
+            peak_ws_sessions: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self, count: usize) {
+        self.bytes.fetch_add(count, Ordering::Relaxed);
+        self.total_bytes.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn redis_error(&self) {
+        self.redis_errors.fetch_add(1, Ordering::Relaxed);
+        self.total_redis_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A WebSocket session was opened, making `connected` sessions in total.
+    pub fn ws_session(&self, connected: usize) {
+        self.ws_sessions.fetch_add(1, Ordering::Relaxed);
+        let mut peak = self.peak_ws_sessions.load(Ordering::Relaxed);
+        while connected > peak {
+            match self.peak_ws_sessions.compare_exchange_weak(peak, connected, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => peak = current,
+            }
+        }
+    }
+
+    pub fn take(&self) -> Summary {
+        Summary {
+            requests: self.requests.swap(0, Ordering::Relaxed),
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            redis_errors: self.redis_errors.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    pub fn lifetime(&self) -> Lifetime {
+        Lifetime {
+            uptime: self.started.elapsed(),
+            requests: self.total_requests.load(Ordering::Relaxed),
+            bytes: self.total_bytes.load(Ordering::Relaxed),
+            redis_errors: self.total_redis_errors.load(Ordering::Relaxed),
+            ws_sessions: self.ws_sessions.load(Ordering::Relaxed),
+            peak_ws_sessions: self.peak_ws_sessions.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use {
+    log::{info, warn},
+    hyper::{
+        Body, HeaderMap, Request, Response, StatusCode,
+        header::{HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE},
+    },
+    futures::{
+        compat::{Future01CompatExt, Stream01CompatExt},
+        stream::StreamExt,
+    },
+    tokio::prelude::future as future01,
+    std::{
+        collections::HashSet,
+        path::{Path, PathBuf},
+        sync::Mutex,
+    },
+    crate::{
+        config::{Config, UploadRule},
+        error::ServeError,
+        request_path,
+        semaphore::Semaphore,
+    },
+};
+
+// Minimal file drop: `PUT /upload/<name>` stores the body as `<name>` in the
+// configured upload directory. The data is collected in a hidden file next to
+// it, which only replaces `<name>` once the upload is complete, so a failed
+// or oversized upload never leaves a truncated file behind.
+//
+// An upload can be sent in parts, or resumed after the connection dropped, by
+// sending the rest of the file with `Content-Range: bytes <start>-<end>/<total>`,
+// where `start` has to be the size that was received so far and the body has
+// to be exactly the announced part. It's complete once `total` bytes have
+// arrived (or with the first request that has no Content-Range); a part with
+// an unknown total (`*`) never completes the upload by itself. A malformed
+// part is refused and its data dropped again. The reply is the size received
+// so far. Only one upload to the same name can run at a time, since they'd
+// share the partial file; others are refused with 409.
+//
+// Without `upload_paths`, any name may be uploaded by clients with the upload
+// role, up to `max_upload_size`. With them, the longest configured prefix of
+// the path decides the role needed and the size allowed, and paths that match
+// none are refused. Names may lead into subdirectories of the upload
+// directory, which have to exist already.
+
+pub const UPLOAD_PREFIX: &'static str = "/upload/";
+// Role needed to upload, unless `upload_paths` says otherwise.
+pub const UPLOAD_ROLE: &'static str = "upload";
+// Body data is collected up to this size before it's written, so that the
+// file only has to be open (and hold one of the `max_open_files` slots) while
+// it's written to, not for as long as the client takes to send.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+// Only plain path components are accepted, so that uploads can't end up
+// outside of the upload directory or overwrite hidden files.
+fn is_valid_name(name: &str) -> bool {
+    name.split('/').all(|component| {
+        !component.is_empty() && !component.starts_with('.') && !component.contains('\\') && !component.contains('\0')
+    })
+}
+
+// The rule that applies to uploads to `path` (the decoded request path), or
+// Forbidden if uploads there aren't allowed.
+pub fn rule(config: &Config, path: &str) -> Result<UploadRule, ServeError> {
+    if config.upload_paths.is_empty() {
+        return Ok(UploadRule { role: UPLOAD_ROLE.to_string(), max_size: config.max_upload_size });
+    }
+    config.upload_paths.iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, rule)| rule.clone())
+        .ok_or(ServeError::Forbidden)
+}
+
+// The rule for `path`, if one of `roles` may upload there.
+pub fn authorize(config: &Config, path: &str, roles: &[String]) -> Result<UploadRule, ServeError> {
+    let rule = rule(config, path)?;
+    if roles.iter().any(|role| *role == rule.role) {
+        Ok(rule)
+    } else {
+        Err(ServeError::Forbidden)
+    }
+}
+
+// The uploads in progress, by target.
+#[derive(Default)]
+pub struct InProgress {
+    targets: Mutex<HashSet<PathBuf>>,
+}
+
+// Marks an upload as in progress until it's dropped.
+pub struct Receiving<'a> {
+    uploads: &'a InProgress,
+    target: PathBuf,
+}
+
+impl InProgress {
+    // None if there's an upload to `target` already.
+    pub fn start(&self, target: &Path) -> Option<Receiving> {
+        if self.targets.lock().unwrap().insert(target.to_path_buf()) {
+            Some(Receiving { uploads: self, target: target.to_path_buf() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Drop for Receiving<'a> {
+    fn drop(&mut self) {
+        self.uploads.targets.lock().unwrap().remove(&self.target);
+    }
+}
+
+// The start and end offsets (both inclusive) and the total size (None for
+// `*`) of a `bytes <start>-<end>/<total>` header.
+fn content_range(header: &HeaderValue) -> Result<(u64, u64, Option<u64>), ServeError> {
+    let invalid = ServeError::BadRequest("Invalid Content-Range");
+    let range = match header.to_str().map(|header| header.trim()) {
+        Ok(header) if header.starts_with("bytes ") => &header["bytes ".len()..],
+        _ => return Err(invalid),
+    };
+    let mut parts = range.splitn(2, '/');
+    let mut bounds = parts.next().unwrap_or("").splitn(2, '-');
+    let start = bounds.next().and_then(|start| start.trim().parse::<u64>().ok());
+    let end = bounds.next().and_then(|end| end.trim().parse::<u64>().ok());
+    let total = match parts.next().map(|total| total.trim()) {
+        Some("*") => Ok(None),
+        Some(total) => total.parse::<u64>().map(Some).map_err(|_| ()),
+        None => Err(()),
+    };
+    match (start, end, total) {
+        (Some(start), Some(end), Ok(total)) if start <= end && total.map(|total| end < total).unwrap_or(true) => Ok((start, end, total)),
+        _ => Err(invalid),
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+}
+
+// Where the data for `target` is collected until the upload is complete.
+// Uploads can't name it themselves, since `is_valid_name` refuses hidden
+// files.
+fn partial_path(target: &Path) -> PathBuf {
+    let name = target.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    target.with_file_name(format!(".{}.part", name))
+}
+
+// Writes `data` to the end of the file at `path`, or replaces its contents
+// if `truncate` is set.
+async fn write(path: &Path, data: Vec<u8>, truncate: bool, open_files: &Semaphore) -> Result<(), ServeError> {
+    let _permit = await!(open_files.acquire());
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).write(true);
+    if truncate {
+        options.truncate(true);
+    } else {
+        options.append(true);
+    }
+    let file = await!(options.open(path.to_path_buf()).compat())?;
+    await!(tokio::io::write_all(file, data).compat())?;
+    Ok(())
+}
+
+// Cuts the partial file at `path` back to `len` bytes, dropping the data of a
+// part that was refused.
+async fn roll_back(path: &Path, len: u64, open_files: &Semaphore) -> Result<(), ServeError> {
+    if len == 0 {
+        let _ = await!(tokio::fs::remove_file(path.to_path_buf()).compat());
+        return Ok(());
+    }
+    let _permit = await!(open_files.acquire());
+    let mut file = await!(tokio::fs::OpenOptions::new().write(true).open(path.to_path_buf()).compat())?;
+    await!(future01::poll_fn(move || file.poll_set_len(len)).compat())?;
+    Ok(())
+}
+
+// `path` is the decoded request path, `max_size` the largest file the rule
+// for it allows. `open_files` limits the files open at the same time.
+pub async fn receive(req: Request<Body>, config: &Config, path: &str, max_size: u64, open_files: &Semaphore, uploads: &InProgress) -> Result<Response<Body>, ServeError> {
+    let directory = config.upload_dir.as_ref().ok_or(ServeError::NotFound)?;
+    let name = &path[UPLOAD_PREFIX.len()..];
+    let target = match request_path::join(directory, &path[UPLOAD_PREFIX.len() - 1..]) {
+        Some(target) if is_valid_name(name) => target,
+        _ => return Err(ServeError::BadRequest("Invalid file name")),
+    };
+    let partial = partial_path(&target);
+    let _receiving = uploads.start(&target).ok_or(ServeError::Conflict)?;
+
+    // The start of the part, the size it has to have (None without
+    // Content-Range) and the size of the whole file (None if unknown).
+    let (start, part_len, total) = match req.headers().get(CONTENT_RANGE) {
+        Some(range) => {
+            let (start, end, total) = content_range(range)?;
+            (start, Some(end - start + 1), total)
+        },
+        None => (0, None, None),
+    };
+    // Refused before anything is touched if the client already says how
+    // much it's going to send.
+    let length = content_length(req.headers());
+    if let (Some(length), Some(part_len)) = (length, part_len) {
+        if length != part_len {
+            return Err(ServeError::BadRequest("Content-Length doesn't match Content-Range"));
+        }
+    }
+    let announced = part_len.or(length).unwrap_or(0);
+    if total.unwrap_or(0) > max_size || start.saturating_add(announced) > max_size {
+        return Err(ServeError::PayloadTooLarge);
+    }
+    if start != 0 {
+        let received = await!(tokio::fs::metadata(partial.clone()).compat()).map(|metadata| metadata.len()).unwrap_or(0);
+        if start != received {
+            // Tell the client where to continue from.
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", received).as_str())
+                .body(Body::empty())?);
+        }
+    }
+
+    // A fresh upload starts the partial file over.
+    let mut truncate = start == 0;
+    let mut size = start;
+    let mut buffer = Vec::new();
+    let mut body = req.into_body().compat();
+    while let Some(chunk) = await!(body.next()) {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Keep what has arrived, so that the upload can be resumed.
+                warn!("Upload {:?} interrupted after {} bytes: {}", target, size, err);
+                await!(write(&partial, buffer, truncate, open_files))?;
+                return Err(ServeError::Internal(err.to_string()));
+            },
+        };
+        size += chunk.len() as u64;
+        if size > max_size {
+            // Only this part goes, the ones accepted before are kept.
+            await!(roll_back(&partial, start, open_files))?;
+            return Err(ServeError::PayloadTooLarge);
+        }
+        if part_len.map(|part_len| size - start > part_len).unwrap_or(false) {
+            await!(roll_back(&partial, start, open_files))?;
+            return Err(ServeError::BadRequest("Body is longer than the Content-Range"));
+        }
+        buffer.extend_from_slice(&chunk);
+        if buffer.len() >= WRITE_BUFFER_SIZE {
+            await!(write(&partial, std::mem::replace(&mut buffer, Vec::new()), truncate, open_files))?;
+            truncate = false;
+        }
+    }
+    await!(write(&partial, buffer, truncate, open_files))?;
+    if part_len.map(|part_len| size - start != part_len).unwrap_or(false) {
+        await!(roll_back(&partial, start, open_files))?;
+        return Err(ServeError::BadRequest("Body is shorter than the Content-Range"));
+    }
+
+    let complete = match (part_len, total) {
+        (None, _) => true,
+        (Some(_), Some(total)) => size == total,
+        (Some(_), None) => false,
+    };
+    if complete {
+        await!(tokio::fs::rename(partial, target.clone()).compat())?;
+        info!("Received upload {:?}, {} bytes", target, size);
+    } else {
+        info!("Received part of upload {:?}, now {} bytes", target, size);
+    }
+
+    Ok(Response::builder()
+        .status(if start == 0 { StatusCode::CREATED } else { StatusCode::OK })
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(size.to_string()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::prelude::{Stream, stream as stream01},
+        crate::tests::{block_on, temp_dir},
+    };
+
+    fn config(name: &str) -> Config {
+        let mut config = Config::default();
+        config.upload_dir = Some(temp_dir(name));
+        config
+    }
+
+    fn put(body: Body, content_range: Option<&str>) -> Request<Body> {
+        let mut req = Request::put("/upload/file.txt");
+        if let Some(range) = content_range {
+            req.header(CONTENT_RANGE, range);
+        }
+        req.body(body).unwrap()
+    }
+
+    // Sends `req`, returning the status and body of the reply.
+    fn upload(config: &Config, req: Request<Body>, max_size: u64) -> Result<(StatusCode, String), ServeError> {
+        let config = config.clone();
+        block_on(async move {
+            let response = match await!(receive(req, &config, "/upload/file.txt", max_size, &Semaphore::new(1), &InProgress::default())) {
+                Ok(response) => response,
+                Err(err) => return Err(err),
+            };
+            let status = response.status();
+            let body = await!(response.into_body().concat2().compat()).unwrap();
+            Ok((status, String::from_utf8(body.to_vec()).unwrap()))
+        })
+    }
+
+    fn target(config: &Config) -> PathBuf {
+        config.upload_dir.as_ref().unwrap().join("file.txt")
+    }
+
+    #[test]
+    fn stores_upload() {
+        let config = config("upload-store");
+        let (status, body) = upload(&config, put(Body::from("hello"), None), 100).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, "5");
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"hello");
+        assert!(!partial_path(&target(&config)).exists());
+    }
+
+    #[test]
+    fn refuses_announced_oversized_upload_up_front() {
+        let config = config("upload-announced");
+        std::fs::write(target(&config), "previous").unwrap();
+        let mut req = put(Body::from("too large"), None);
+        req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("9"));
+        match upload(&config, req, 4) {
+            Err(ServeError::PayloadTooLarge) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"previous");
+    }
+
+    #[test]
+    fn oversized_upload_leaves_target_alone() {
+        let config = config("upload-oversized");
+        std::fs::write(target(&config), "previous").unwrap();
+        let body = Body::wrap_stream(stream01::iter_ok::<_, std::io::Error>(vec!["abc", "def"]));
+        match upload(&config, put(body, None), 4) {
+            Err(ServeError::PayloadTooLarge) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"previous");
+        assert!(!partial_path(&target(&config)).exists());
+    }
+
+    #[test]
+    fn interrupted_upload_can_be_resumed() {
+        let config = config("upload-resume");
+        std::fs::write(target(&config), "previous").unwrap();
+        let body = Body::wrap_stream(stream01::iter_result(vec![
+            Ok("abc"),
+            Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "gone")),
+        ]));
+        assert!(upload(&config, put(body, Some("bytes 0-5/6")), 100).is_err());
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"previous");
+
+        let (status, body) = upload(&config, put(Body::from("xyz"), Some("bytes 1-3/6")), 100).unwrap();
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(body, "");
+
+        let (status, body) = upload(&config, put(Body::from("def"), Some("bytes 3-5/6")), 100).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "6");
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"abcdef");
+        assert!(!partial_path(&target(&config)).exists());
+    }
+
+    #[test]
+    fn parts_are_kept_until_complete() {
+        let config = config("upload-parts");
+        let (status, _) = upload(&config, put(Body::from("abc"), Some("bytes 0-2/6")), 100).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(!target(&config).exists());
+        assert_eq!(std::fs::read(partial_path(&target(&config))).unwrap(), b"abc");
+    }
+
+    fn is_bad_request<T: std::fmt::Debug>(result: Result<T, ServeError>) -> bool {
+        match result {
+            Err(ServeError::BadRequest(_)) => true,
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parts_have_to_match_their_range() {
+        let config = config("upload-mismatch");
+        upload(&config, put(Body::from("abc"), Some("bytes 0-2/9")), 100).unwrap();
+
+        // Longer than announced, streamed so that it's only noticed while
+        // writing.
+        let body = Body::wrap_stream(stream01::iter_ok::<_, std::io::Error>(vec!["def", "ghi"]));
+        assert!(is_bad_request(upload(&config, put(body, Some("bytes 3-5/9")), 100)));
+        assert_eq!(std::fs::read(partial_path(&target(&config))).unwrap(), b"abc");
+
+        assert!(is_bad_request(upload(&config, put(Body::from("de"), Some("bytes 3-5/9")), 100)));
+        assert_eq!(std::fs::read(partial_path(&target(&config))).unwrap(), b"abc");
+
+        let mut req = put(Body::from("def"), Some("bytes 3-5/9"));
+        req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("4"));
+        assert!(is_bad_request(upload(&config, req, 100)));
+
+        assert!(is_bad_request(upload(&config, put(Body::from("defghi"), Some("bytes 3-8/6")), 100)));
+        assert!(!target(&config).exists());
+
+        let (status, body) = upload(&config, put(Body::from("defghi"), Some("bytes 3-8/9")), 100).unwrap();
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "9"));
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"abcdefghi");
+    }
+
+    #[test]
+    fn oversized_part_keeps_the_earlier_ones() {
+        let config = config("upload-oversized-part");
+        upload(&config, put(Body::from("abc"), Some("bytes 0-2/*")), 8).unwrap();
+        // More than announced, and too much in total, which is noticed
+        // first.
+        let body = Body::wrap_stream(stream01::iter_ok::<_, std::io::Error>(vec!["def", "ghi"]));
+        match upload(&config, put(body, Some("bytes 3-5/*")), 8) {
+            Err(ServeError::PayloadTooLarge) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(std::fs::read(partial_path(&target(&config))).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn unknown_total_does_not_complete() {
+        let config = config("upload-unknown-total");
+        upload(&config, put(Body::from("abc"), Some("bytes 0-2/*")), 100).unwrap();
+        assert!(!target(&config).exists());
+        upload(&config, put(Body::from("def"), Some("bytes 3-5/6")), 100).unwrap();
+        assert_eq!(std::fs::read(target(&config)).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn one_upload_per_name_at_a_time() {
+        let uploads = InProgress::default();
+        let receiving = uploads.start(Path::new("/uploads/file.txt"));
+        assert!(receiving.is_some());
+        assert!(uploads.start(Path::new("/uploads/file.txt")).is_none());
+        assert!(uploads.start(Path::new("/uploads/other.txt")).is_some());
+        drop(receiving);
+        assert!(uploads.start(Path::new("/uploads/file.txt")).is_some());
+    }
+
+    #[test]
+    fn refuses_invalid_names() {
+        let config = config("upload-names");
+        let result = block_on(async move {
+            await!(receive(Request::put("/upload/.hidden").body(Body::empty()).unwrap(), &config, "/upload/.hidden", 100, &Semaphore::new(1), &InProgress::default()))
+        });
+        match result {
+            Err(ServeError::BadRequest(_)) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    fn upload_paths() -> Config {
+        let mut config = Config::default();
+        config.max_upload_size = 1000;
+        config.upload_paths.insert(String::from("/upload/"), UploadRule { role: String::from("upload"), max_size: 100 });
+        config.upload_paths.insert(String::from("/upload/photos/"), UploadRule { role: String::from("photos"), max_size: 10 });
+        config
+    }
+
+    fn roles(roles: &[&str]) -> Vec<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    }
+
+    #[test]
+    fn default_rule_without_upload_paths() {
+        let mut config = Config::default();
+        config.max_upload_size = 1000;
+        let rule = rule(&config, "/upload/anything/file.txt").unwrap();
+        assert_eq!(rule, UploadRule { role: String::from(UPLOAD_ROLE), max_size: 1000 });
+    }
+
+    #[test]
+    fn longest_prefix_decides() {
+        let config = upload_paths();
+        assert_eq!(rule(&config, "/upload/file.txt").unwrap().max_size, 100);
+        assert_eq!(rule(&config, "/upload/photos/cat.jpg").unwrap().max_size, 10);
+        assert_eq!(rule(&config, "/upload/photos/cat.jpg").unwrap().role, "photos");
+    }
+
+    #[test]
+    fn paths_without_rule_are_forbidden() {
+        let mut config = upload_paths();
+        config.upload_paths.remove("/upload/");
+        match rule(&config, "/upload/file.txt") {
+            Err(ServeError::Forbidden) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn needs_the_rules_role() {
+        let config = upload_paths();
+        assert!(authorize(&config, "/upload/file.txt", &roles(&["upload"])).is_ok());
+        assert!(authorize(&config, "/upload/photos/cat.jpg", &roles(&["admin", "photos"])).is_ok());
+        match authorize(&config, "/upload/photos/cat.jpg", &roles(&["upload"])) {
+            Err(ServeError::Forbidden) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(authorize(&config, "/upload/file.txt", &[]).is_err());
+    }
+
+    #[test]
+    fn rules_size_limit_applies() {
+        let mut config = upload_paths();
+        config.upload_dir = Some(temp_dir("upload-rules"));
+        let limit = rule(&config, "/upload/file.txt").unwrap().max_size;
+        assert!(upload(&config, put(Body::from(vec![b'x'; 50]), None), limit).is_ok());
+        let limit = rule(&config, "/upload/photos/cat.jpg").unwrap().max_size;
+        match upload(&config, put(Body::from(vec![b'x'; 50]), None), limit) {
+            Err(ServeError::PayloadTooLarge) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(content_range(&HeaderValue::from_static("bytes 3-5/6")).unwrap(), (3, 5, Some(6)));
+        assert_eq!(content_range(&HeaderValue::from_static("bytes 0-9/*")).unwrap(), (0, 9, None));
+        assert!(content_range(&HeaderValue::from_static("bytes 3-5")).is_err());
+        assert!(content_range(&HeaderValue::from_static("bytes 3-/6")).is_err());
+        assert!(content_range(&HeaderValue::from_static("bytes 5-3/6")).is_err());
+        assert!(content_range(&HeaderValue::from_static("bytes 3-6/6")).is_err());
+        assert!(content_range(&HeaderValue::from_static("items 3-5/6")).is_err());
+    }
+}
@@ -1,43 +1,432 @@
-use log::{info, debug, error};
+use log::{info, debug, warn, error};
 use curl::easy::Easy;
-use redis::{Commands, PipelineCommands};
-use std::io::{BufReader, BufRead};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufRead, BufWriter, Write};
+
+mod scan;
+mod store;
+
+use scan::{LineSource, ScanSource};
+use store::{IButtonStore, RedisIButtonStore};
 
 const DEFAULT_URL: &'static str = "<RETRACTED>";
-const IBUTTONS_KEY: &'static str = "ibuttons";
+const REDIS_URL: &'static str = "redis://127.0.0.1/";
+// What a scan is decided as when Redis can't be asked: "closed" (the
+// default) denies it, "open" grants it, so that members aren't locked out
+// during an outage.
+const REDIS_FAILURE_ENV: &'static str = "IBUTTONS_REDIS_FAILURE";
 
-fn main() {
-    env_logger::init();
-    info!("Fetching iButtons...");
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FailurePolicy {
+    Open,
+    Closed,
+}
 
+impl FailurePolicy {
+    fn from_env() -> Self {
+        FailurePolicy::parse(std::env::var(REDIS_FAILURE_ENV).ok().as_ref().map(String::as_str))
+    }
+
+    fn parse(policy: Option<&str>) -> Self {
+        match policy {
+            Some("open") => FailurePolicy::Open,
+            Some("closed") | Some("") | None => FailurePolicy::Closed,
+            Some(policy) => panic!("Invalid {} {:?}, expected \"open\" or \"closed\".", REDIS_FAILURE_ENV, policy),
+        }
+    }
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, curl::Error> {
     let mut ibuttons = Vec::new();
     let mut easy = Easy::new();
-    let url: String = DEFAULT_URL.to_string();
 
-    easy.url(&url).expect("Invalid URL.");
-    easy.fail_on_error(true).unwrap();
+    easy.url(url)?;
+    easy.fail_on_error(true)?;
     {
         let mut transfer = easy.transfer();
         transfer.write_function(|data| {
             ibuttons.extend_from_slice(data);
             Ok(data.len())
-        }).unwrap();
-        transfer.perform().unwrap();
+        })?;
+        transfer.perform()?;
+    }
+    Ok(ibuttons)
+}
+
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// One ID per line, optionally followed by whitespace and the owner's name;
+// blank lines are skipped, anything else that doesn't look like an ID is
+// logged and dropped.
+fn parse_ids(url: &str, data: &[u8]) -> Vec<(String, Option<String>)> {
+    BufReader::new(data).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| {
+            let line = line.trim();
+            let mut columns = line.splitn(2, char::is_whitespace);
+            let id = columns.next().unwrap_or("").to_string();
+            let name = columns.next().map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+            if id.is_empty() {
+                None
+            } else {
+                Some((id, name))
+            }
+        })
+        .filter(|(id, _)| {
+            let valid = is_valid_id(id);
+            if !valid {
+                warn!("{}: ignoring invalid iButton ID {:?}", url, id);
+            }
+            valid
+        })
+        .collect()
+}
+
+fn store_ibuttons<S: IButtonStore>(store: &mut S, ids: &[String]) -> redis::RedisResult<()> {
+    if ids.len() == 0 {
+        info!("No iButtons found.");
+    }
+    store.replace_ibuttons(ids)
+}
+
+// Whether the scanned iButton is let in.
+fn decide<T: IButtonStore>(store: &mut T, id: &str, policy: FailurePolicy) -> bool {
+    match store.contains_ibutton(id) {
+        Ok(true) => {
+            // Names are only cosmetic, so failing to look one up falls back
+            // to the ID.
+            let name = store.name(id).unwrap_or(None).unwrap_or_else(|| id.to_string());
+            info!("Scan {}: granted, iButton of {}", id, name);
+            true
+        },
+        Ok(false) => {
+            warn!("Scan {}: denied, unknown iButton", id);
+            false
+        },
+        Err(err) => {
+            let granted = policy == FailurePolicy::Open;
+            error!("Scan {}: REDIS IS UNAVAILABLE ({}), {} by the fail-{} policy", id, err, if granted { "granted" } else { "denied" }, if granted { "open" } else { "closed" });
+            granted
+        },
+    }
+}
+
+// Checks every scan against the stored iButtons until the source runs dry.
+// Nothing acts on the decision yet; there's no door to open from here, so
+// it's only counted. Returns how many scans were granted.
+fn check_scans<S: ScanSource, T: IButtonStore>(source: &mut S, store: &mut T, policy: FailurePolicy) -> std::io::Result<usize> {
+    let mut granted = 0;
+    while let Some(id) = source.next_scan()? {
+        if decide(store, &id, policy) {
+            granted += 1;
+        }
+    }
+    Ok(granted)
+}
+
+// Writes the stored iButtons to `path`, in the same format as the sources,
+// so that the file can be imported again or served as a source. Returns how
+// many were written.
+fn export<T: IButtonStore>(store: &mut T, path: &str) -> std::io::Result<usize> {
+    let mut ids = store.ibuttons().expect("Failed reading iButtons from redis");
+    ids.sort();
+    let names = store.names().expect("Failed reading iButton names from redis").into_iter().collect::<BTreeMap<String, String>>();
+    let mut file = BufWriter::new(File::create(path)?);
+    for id in &ids {
+        match names.get(id) {
+            Some(name) => writeln!(file, "{} {}", id, name)?,
+            None => writeln!(file, "{}", id)?,
+        }
     }
+    file.flush()?;
+    Ok(ids.len())
+}
+
+// Reads iButtons from a file written by `export` (or any other file in the
+// source format) and adds them to the stored ones, or replaces them. Invalid
+// IDs are dropped, like during a sync. Returns how many were read.
+fn import<T: IButtonStore>(store: &mut T, path: &str, replace: bool) -> std::io::Result<usize> {
+    let data = std::fs::read(path)?;
+    let entries = parse_ids(path, &data);
+    let ids = entries.iter().map(|(id, _)| id.clone()).collect::<Vec<String>>();
+    let names = entries.into_iter()
+        .filter_map(|(id, name)| name.map(|name| (id, name)))
+        .collect::<Vec<(String, String)>>();
+    if replace {
+        // An empty (or truncated to nothing) file would lock everyone out.
+        if ids.is_empty() {
+            panic!("{} contains no iButtons, refusing to replace the stored ones.", path);
+        }
+        store.replace_ibuttons(&ids).expect("Failed storing iButtons in redis");
+        store.replace_names(&names).expect("Failed storing iButton names in redis");
+    } else {
+        store.add_ibuttons(&ids).expect("Failed storing iButtons in redis");
+        store.add_names(&names).expect("Failed storing iButton names in redis");
+    }
+    Ok(ids.len())
+}
+
+#[cfg(feature = "serial")]
+fn serve_serial<T: IButtonStore>(device: &str, store: &mut T, policy: FailurePolicy) -> std::io::Result<usize> {
+    let mut source = scan::open_serial(device, 9600)?;
+    check_scans(&mut source, store, policy)
+}
+
+#[cfg(not(feature = "serial"))]
+fn serve_serial<T: IButtonStore>(_device: &str, _store: &mut T, _policy: FailurePolicy) -> std::io::Result<usize> {
+    panic!("Built without serial support, enable the \"serial\" feature.");
+}
 
-    let reader = BufReader::new(&*ibuttons);
+// Fetches the membership lists and stores the union of them. If only some of
+// the lists could be fetched, their iButtons are added to the stored ones.
+fn sync<T: IButtonStore>(store: &mut T, urls: &[String]) -> Result<(), String> {
+    info!("Fetching iButtons from {} source(s)...", urls.len());
 
-    let ids = reader.lines().filter_map(|id| id.ok()).collect::<Vec<String>>();
+    let mut ids = BTreeSet::new();
+    let mut names = BTreeMap::new();
+    let mut failed = 0;
+    for url in urls {
+        match fetch(url) {
+            Ok(data) => {
+                let source_ids = parse_ids(url, &data);
+                info!("{}: {} iButtons", url, source_ids.len());
+                for (id, name) in source_ids {
+                    if let Some(name) = name {
+                        names.insert(id.clone(), name);
+                    }
+                    ids.insert(id);
+                }
+            },
+            Err(err) => {
+                error!("Failed fetching {}: {}", url, err);
+                failed += 1;
+            }
+        }
+    }
+    let ids = ids.into_iter().collect::<Vec<String>>();
+    let names = names.into_iter().collect::<Vec<(String, String)>>();
     debug!("ids: {:?}", ids);
 
-    let client = redis::Client::open("redis://127.0.0.1/").expect("Failed to set up redis client");
-    let con = client.get_connection().expect("Failed to connect to redis");
-    if ids.len() > 0 {
-        redis::transaction(&con, &[IBUTTONS_KEY], |pipe| {
-            pipe.del(IBUTTONS_KEY).sadd(IBUTTONS_KEY, ids.clone()).query::<Option<()>>(&con)
-        }).expect("Failed executing redis transaction");
+    let stored = if failed == urls.len() {
+        return Err(String::from("All iButton sources failed, leaving the stored iButtons untouched."));
+    } else if failed > 0 {
+        // Replacing the set now would lock out everyone from the failed
+        // sources, so only add what we got.
+        warn!("{} of {} sources failed, only adding iButtons.", failed, urls.len());
+        store.add_ibuttons(&ids).and_then(|_| store.add_names(&names))
     } else {
-        info!("No iButtons found.");
-        con.del::<_, i32>(IBUTTONS_KEY).expect("Failed deleting iButtons in redis");
+        store_ibuttons(store, &ids).and_then(|_| store.replace_names(&names))
+    };
+    stored.map_err(|err| format!("Failed storing iButtons in redis: {}", err))?;
+    store.record_sync().map_err(|err| format!("Failed storing the sync time in redis: {}", err))
+}
+
+fn main() {
+    env_logger::init();
+
+    // `--scan <file>` (a file or FIFO) and `--serial <device>` read live scans
+    // instead of syncing. `--export <file>` saves the stored iButtons, and
+    // `--import <file>` adds them back (`--import-replace <file>` replaces the
+    // stored ones instead), e.g. for backups or moving to another Redis.
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let scanning = args.len() == 2 && (args[0] == "--scan" || args[0] == "--serial");
+    let snapshot = args.len() == 2 && (args[0] == "--export" || args[0] == "--import" || args[0] == "--import-replace");
+    info!("ibuttons {}", env!("CARGO_PKG_VERSION"));
+    info!("  mode: {}", if scanning {
+        format!("checking scans from {}", args[1])
+    } else if snapshot {
+        format!("{} {}", &args[0][2..], args[1])
+    } else {
+        String::from("sync")
+    });
+    info!("  redis: {}, key: {}", REDIS_URL, store::prefixed(store::IBUTTONS_KEY));
+    info!("  serial support: {}", cfg!(feature = "serial"));
+    if scanning {
+        let policy = FailurePolicy::from_env();
+        info!("  on redis failure: {:?}", policy);
+        let mut store = RedisIButtonStore::connect(REDIS_URL).expect("Failed to connect to redis");
+        let result = if args[0] == "--scan" {
+            let mut source = LineSource::open(std::path::Path::new(&args[1])).expect("Failed opening scan source");
+            check_scans(&mut source, &mut store, policy)
+        } else {
+            serve_serial(&args[1], &mut store, policy)
+        };
+        let granted = result.expect("Failed reading scans");
+        info!("Scan source exhausted, {} scan(s) granted.", granted);
+        return;
+    }
+    if snapshot {
+        let mut store = RedisIButtonStore::connect(REDIS_URL).expect("Failed to connect to redis");
+        if args[0] == "--export" {
+            let count = export(&mut store, &args[1]).expect("Failed writing the export");
+            info!("Exported {} iButtons to {}.", count, args[1]);
+        } else {
+            let replace = args[0] == "--import-replace";
+            let count = import(&mut store, &args[1], replace).expect("Failed reading the import");
+            info!("{} {} iButtons from {}.", if replace { "Replaced the stored iButtons with" } else { "Added" }, count, args[1]);
+        }
+        return;
+    }
+
+    // The membership lists to sync are given on the command line, falling
+    // back to the default one. The stored set is the union of all of them.
+    let mut urls = args;
+    if urls.is_empty() {
+        urls.push(DEFAULT_URL.to_string());
+    }
+
+    let mut store = RedisIButtonStore::connect(REDIS_URL).expect("Failed to connect to redis");
+    if let Err(message) = sync(&mut store, &urls) {
+        // Also left in Redis, where the dashboard can show it. The next
+        // successful sync removes it again.
+        if let Err(err) = store.record_error(&message) {
+            warn!("Failed storing the error in redis: {}", err);
+        }
+        error!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::MockScanSource;
+    use crate::store::MemoryIButtonStore;
+
+    // A fresh file below the system's temp directory.
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ibuttons-{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn stored(store: &mut MemoryIButtonStore) -> Vec<String> {
+        let mut ids = store.ibuttons().unwrap();
+        ids.sort();
+        ids
+    }
+
+    fn known(ids: &[&str]) -> MemoryIButtonStore {
+        let mut store = MemoryIButtonStore::default();
+        store.add_ibuttons(&strings(ids)).unwrap();
+        store
+    }
+
+    fn scans(ids: &[&str]) -> MockScanSource {
+        MockScanSource { scans: strings(ids).into_iter().collect() }
+    }
+
+    #[test]
+    fn decides_by_stored_ibuttons() {
+        let mut store = known(&["A1"]);
+        assert!(decide(&mut store, "A1", FailurePolicy::Closed));
+        assert!(!decide(&mut store, "B2", FailurePolicy::Open));
+    }
+
+    #[test]
+    fn parses_the_failure_policy() {
+        assert_eq!(FailurePolicy::parse(Some("open")), FailurePolicy::Open);
+        assert_eq!(FailurePolicy::parse(Some("closed")), FailurePolicy::Closed);
+        assert_eq!(FailurePolicy::parse(Some("")), FailurePolicy::Closed);
+        assert_eq!(FailurePolicy::parse(None), FailurePolicy::Closed);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid IBUTTONS_REDIS_FAILURE \"Open\"")]
+    fn refuses_unknown_failure_policies() {
+        FailurePolicy::parse(Some("Open"));
+    }
+
+    #[test]
+    fn failure_policy_decides_while_redis_is_down() {
+        let mut store = known(&["A1"]);
+        store.unavailable = true;
+        assert!(!decide(&mut store, "A1", FailurePolicy::Closed));
+        assert!(decide(&mut store, "B2", FailurePolicy::Open));
+    }
+
+    #[test]
+    fn checks_every_scan() {
+        let mut store = known(&["A1", "C3"]);
+        let mut source = scans(&["A1", "B2", "C3", "A1"]);
+        assert_eq!(check_scans(&mut source, &mut store, FailurePolicy::Closed).unwrap(), 3);
+        assert!(source.scans.is_empty());
+    }
+
+    #[test]
+    fn reads_scans_line_by_line() {
+        let mut source = LineSource::new("test", std::io::Cursor::new("A1\n\n  B2 \nnot-an-id\r\nC3\r\n"));
+        let mut store = known(&["A1", "B2", "C3"]);
+        assert_eq!(check_scans(&mut source, &mut store, FailurePolicy::Closed).unwrap(), 3);
+        assert_eq!(source.next_scan().unwrap(), None);
+    }
+
+    #[test]
+    fn sync_replaces_stored_ibuttons() {
+        let mut store = MemoryIButtonStore::default();
+        store.add_ibuttons(&strings(&["OLD"])).unwrap();
+        store_ibuttons(&mut store, &strings(&["B2", "A1"])).unwrap();
+        assert_eq!(stored(&mut store), strings(&["A1", "B2"]));
+    }
+
+    #[test]
+    fn import_adds_or_replaces() {
+        let path = temp_file("import", "A1 Alice\n\nnot-an-id\nB2\n");
+        let mut store = MemoryIButtonStore::default();
+        store.add_ibuttons(&strings(&["OLD"])).unwrap();
+
+        assert_eq!(import(&mut store, &path, false).unwrap(), 2);
+        assert_eq!(stored(&mut store), strings(&["A1", "B2", "OLD"]));
+        assert_eq!(store.name("A1").unwrap(), Some(String::from("Alice")));
+
+        assert_eq!(import(&mut store, &path, true).unwrap(), 2);
+        assert_eq!(stored(&mut store), strings(&["A1", "B2"]));
+    }
+
+    #[test]
+    #[should_panic(expected = "contains no iButtons")]
+    fn import_refuses_to_replace_with_nothing() {
+        let path = temp_file("import-empty", "\n");
+        import(&mut MemoryIButtonStore::default(), &path, true).unwrap();
+    }
+
+    #[test]
+    fn export_can_be_imported_again() {
+        let mut store = MemoryIButtonStore::default();
+        store.add_ibuttons(&strings(&["B2", "A1"])).unwrap();
+        store.add_names(&[(String::from("A1"), String::from("Alice Example"))]).unwrap();
+        let path = temp_file("export", "");
+        assert_eq!(export(&mut store, &path).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "A1 Alice Example\nB2\n");
+
+        let mut imported = MemoryIButtonStore::default();
+        import(&mut imported, &path, true).unwrap();
+        assert_eq!(imported.ids, store.ids);
+        assert_eq!(imported.names, store.names);
+    }
+
+    #[test]
+    fn successful_sync_clears_the_last_error() {
+        let mut store = MemoryIButtonStore::default();
+        store.record_error("All iButton sources failed").unwrap();
+        assert_eq!(store.last_error, Some(String::from("All iButton sources failed")));
+        store.record_sync().unwrap();
+        assert!(store.synced);
+        assert_eq!(store.last_error, None);
+    }
+
+    #[test]
+    fn sync_fails_if_every_source_does() {
+        let mut store = known(&["A1"]);
+        assert!(sync(&mut store, &strings(&["file:///nonexistent/ibuttons.txt"])).is_err());
+        assert_eq!(stored(&mut store), strings(&["A1"]));
+        assert!(!store.synced);
     }
 }
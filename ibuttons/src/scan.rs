@@ -0,0 +1,87 @@
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// Live iButton scans, as they come from a reader. Readers send one ID per
+// line; whatever produces those lines (a serial device, a FIFO fed by a bench
+// setup, a test) is a ScanSource.
+
+pub trait ScanSource {
+    // Blocks until the next scan. Returns None once the source is exhausted.
+    fn next_scan(&mut self) -> io::Result<Option<String>>;
+}
+
+pub struct LineSource<R> {
+    name: String,
+    reader: R,
+}
+
+impl <R: BufRead> LineSource<R> {
+    pub fn new(name: &str, reader: R) -> Self {
+        LineSource {
+            name: name.to_string(),
+            reader,
+        }
+    }
+}
+
+impl LineSource<BufReader<File>> {
+    // For files and FIFOs. A FIFO yields scans for as long as a writer keeps
+    // it open.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(LineSource::new(&path.to_string_lossy(), BufReader::new(File::open(path)?)))
+    }
+}
+
+impl <R: BufRead> ScanSource for LineSource<R> {
+    fn next_scan(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {},
+                // Serial ports time out when nobody scans for a while.
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut || err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                    warn!("{}: ignoring line that isn't valid UTF-8", self.name);
+                    continue;
+                },
+                Err(err) => return Err(err),
+            }
+            let id = line.trim();
+            if id.is_empty() {
+                continue;
+            }
+            if !crate::is_valid_id(id) {
+                warn!("{}: ignoring invalid scan {:?}", self.name, id);
+                continue;
+            }
+            debug!("{}: scanned {}", self.name, id);
+            return Ok(Some(id.to_string()));
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+pub fn open_serial(device: &str, baud_rate: u32) -> io::Result<LineSource<BufReader<Box<dyn serialport::SerialPort>>>> {
+    let mut settings = serialport::SerialPortSettings::default();
+    settings.baud_rate = baud_rate;
+    settings.timeout = std::time::Duration::from_secs(60);
+    let port = serialport::open_with_settings(device, &settings)?;
+    Ok(LineSource::new(device, BufReader::new(port)))
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockScanSource {
+    pub scans: std::collections::VecDeque<String>,
+}
+
+#[cfg(test)]
+impl ScanSource for MockScanSource {
+    fn next_scan(&mut self) -> io::Result<Option<String>> {
+        Ok(self.scans.pop_front())
+    }
+}
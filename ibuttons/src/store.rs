@@ -0,0 +1,239 @@
+use log::warn;
+use redis::{Commands, PipelineCommands, RedisResult};
+pub use rediskeys::prefixed;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Where the synced iButton IDs end up. The real implementation writes them to
+// Redis; the in-memory one lets the sync logic run without a Redis server.
+
+pub const IBUTTONS_KEY: &'static str = "ibuttons";
+// Hash from iButton ID to the display name of its owner, for the IDs whose
+// source lists one.
+pub const NAMES_KEY: &'static str = "ibutton_names";
+// Hash from sync job to the Unix time of its last successful run.
+pub const LAST_SYNC_KEY: &'static str = "last_sync";
+// Hash from sync job to its last failure as JSON ({"time": ..., "message":
+// ...}), removed again by the next successful run.
+pub const LAST_ERROR_KEY: &'static str = "last_error";
+const JOB_NAME: &'static str = "ibuttons";
+
+// How often a transaction is attempted if the connection drops, e.g. because
+// Redis is restarted during the sync, and how long to wait before the first
+// retry (doubled for each further one).
+const TRANSACTION_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+pub trait IButtonStore {
+    // Replaces the complete set of stored iButton IDs with `ids`.
+    fn replace_ibuttons(&mut self, ids: &[String]) -> RedisResult<()>;
+
+    // Adds `ids` to the stored iButton IDs, keeping all the existing ones.
+    fn add_ibuttons(&mut self, ids: &[String]) -> RedisResult<()>;
+
+    // Whether `id` is one of the stored iButton IDs.
+    fn contains_ibutton(&mut self, id: &str) -> RedisResult<bool>;
+
+    // All stored iButton IDs, and all stored names.
+    fn ibuttons(&mut self) -> RedisResult<Vec<String>>;
+    fn names(&mut self) -> RedisResult<Vec<(String, String)>>;
+
+    // Like the two above, for the names of the iButtons' owners.
+    fn replace_names(&mut self, names: &[(String, String)]) -> RedisResult<()>;
+    fn add_names(&mut self, names: &[(String, String)]) -> RedisResult<()>;
+
+    fn name(&mut self, id: &str) -> RedisResult<Option<String>>;
+    // Notes the current time as the time of the last sync, and forgets the
+    // last error.
+    fn record_sync(&mut self) -> RedisResult<()>;
+    // Notes why the sync failed.
+    fn record_error(&mut self, message: &str) -> RedisResult<()>;
+}
+
+pub struct RedisIButtonStore {
+    client: redis::Client,
+    con: redis::Connection,
+    key: String,
+    names_key: String,
+}
+
+impl RedisIButtonStore {
+    pub fn connect(url: &str) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisIButtonStore {
+            con: client.get_connection()?,
+            client,
+            key: prefixed(IBUTTONS_KEY),
+            names_key: prefixed(NAMES_KEY),
+        })
+    }
+
+    // Runs `transaction` (or any other write), reconnecting and trying again
+    // if the connection fails. Errors reported by Redis itself aren't
+    // retried.
+    fn retry_transaction<T, F: FnMut(&redis::Connection) -> RedisResult<T>>(&mut self, mut transaction: F) -> RedisResult<T> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match transaction(&self.con) {
+                Err(ref err) if attempt < TRANSACTION_ATTEMPTS && (err.is_io_error() || err.is_connection_dropped()) => {
+                    warn!("Redis connection failed ({}), retrying in {}s.", err, backoff.as_secs());
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                    match self.client.get_connection() {
+                        Ok(con) => self.con = con,
+                        Err(err) => warn!("Reconnecting to redis failed: {}", err),
+                    }
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+impl IButtonStore for RedisIButtonStore {
+    fn replace_ibuttons(&mut self, ids: &[String]) -> RedisResult<()> {
+        let key = self.key.clone();
+        let key = key.as_str();
+        self.retry_transaction(|con| {
+            if ids.len() > 0 {
+                redis::transaction(con, &[key], |pipe| {
+                    pipe.del(key).sadd(key, ids).query::<Option<()>>(con)
+                })
+            } else {
+                con.del::<_, i32>(key).map(|_| ())
+            }
+        })
+    }
+
+    fn add_ibuttons(&mut self, ids: &[String]) -> RedisResult<()> {
+        if ids.len() == 0 {
+            return Ok(());
+        }
+        let key = self.key.clone();
+        // Adding is idempotent, so a retry can't add anything twice.
+        self.retry_transaction(|con| con.sadd::<_, _, i32>(key.as_str(), ids).map(|_| ()))
+    }
+
+    fn contains_ibutton(&mut self, id: &str) -> RedisResult<bool> {
+        self.con.sismember(self.key.as_str(), id)
+    }
+
+    fn ibuttons(&mut self) -> RedisResult<Vec<String>> {
+        self.con.smembers(self.key.as_str())
+    }
+
+    fn names(&mut self) -> RedisResult<Vec<(String, String)>> {
+        self.con.hgetall(self.names_key.as_str())
+    }
+
+    fn replace_names(&mut self, names: &[(String, String)]) -> RedisResult<()> {
+        let key = self.names_key.clone();
+        let key = key.as_str();
+        self.retry_transaction(|con| {
+            if names.len() > 0 {
+                redis::transaction(con, &[key], |pipe| {
+                    pipe.del(key).hset_multiple(key, names).query::<Option<()>>(con)
+                })
+            } else {
+                con.del::<_, i32>(key).map(|_| ())
+            }
+        })
+    }
+
+    fn add_names(&mut self, names: &[(String, String)]) -> RedisResult<()> {
+        if names.len() == 0 {
+            return Ok(());
+        }
+        let key = self.names_key.clone();
+        self.retry_transaction(|con| con.hset_multiple::<_, _, _, ()>(key.as_str(), names))
+    }
+
+    fn name(&mut self, id: &str) -> RedisResult<Option<String>> {
+        self.con.hget(self.names_key.as_str(), id)
+    }
+
+    fn record_sync(&mut self) -> RedisResult<()> {
+        let now = unix_time();
+        self.retry_transaction(|con| {
+            redis::pipe()
+                .hset(prefixed(LAST_SYNC_KEY), JOB_NAME, now).ignore()
+                .hdel(prefixed(LAST_ERROR_KEY), JOB_NAME).ignore()
+                .query(con)
+        })
+    }
+
+    fn record_error(&mut self, message: &str) -> RedisResult<()> {
+        let error = serde_json::json!({ "time": unix_time(), "message": message }).to_string();
+        self.retry_transaction(|con| con.hset(prefixed(LAST_ERROR_KEY), JOB_NAME, error.as_str()))
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryIButtonStore {
+    pub ids: std::collections::HashSet<String>,
+    pub names: std::collections::HashMap<String, String>,
+    pub synced: bool,
+    pub last_error: Option<String>,
+    // Makes lookups fail, like they do while Redis is down.
+    pub unavailable: bool,
+}
+
+#[cfg(test)]
+impl IButtonStore for MemoryIButtonStore {
+    fn replace_ibuttons(&mut self, ids: &[String]) -> RedisResult<()> {
+        self.ids = ids.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn add_ibuttons(&mut self, ids: &[String]) -> RedisResult<()> {
+        self.ids.extend(ids.iter().cloned());
+        Ok(())
+    }
+
+    fn contains_ibutton(&mut self, id: &str) -> RedisResult<bool> {
+        if self.unavailable {
+            return Err(redis::RedisError::from((redis::ErrorKind::IoError, "Redis is unavailable")));
+        }
+        Ok(self.ids.contains(id))
+    }
+
+    fn ibuttons(&mut self) -> RedisResult<Vec<String>> {
+        Ok(self.ids.iter().cloned().collect())
+    }
+
+    fn names(&mut self) -> RedisResult<Vec<(String, String)>> {
+        Ok(self.names.iter().map(|(id, name)| (id.clone(), name.clone())).collect())
+    }
+
+    fn replace_names(&mut self, names: &[(String, String)]) -> RedisResult<()> {
+        self.names = names.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn add_names(&mut self, names: &[(String, String)]) -> RedisResult<()> {
+        self.names.extend(names.iter().cloned());
+        Ok(())
+    }
+
+    fn name(&mut self, id: &str) -> RedisResult<Option<String>> {
+        Ok(self.names.get(id).cloned())
+    }
+
+    fn record_sync(&mut self) -> RedisResult<()> {
+        self.synced = true;
+        self.last_error = None;
+        Ok(())
+    }
+
+    fn record_error(&mut self, message: &str) -> RedisResult<()> {
+        self.last_error = Some(message.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use std::env;
+
+// Namespace for all Redis keys, shared by cellardoor and the sync jobs, so
+// that several spaces can use one Redis instance. Keys become
+// `<prefix>:<key>`, or stay unchanged if it's empty.
+pub const PREFIX_ENV: &'static str = "REDIS_PREFIX";
+
+pub fn prefixed(key: &str) -> String {
+    match env::var(PREFIX_ENV) {
+        Ok(ref prefix) if !prefix.is_empty() => format!("{}:{}", prefix, key),
+        _ => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single test, since the tests of a crate share the environment.
+    #[test]
+    fn prefixes_keys_if_configured() {
+        env::remove_var(PREFIX_ENV);
+        assert_eq!(prefixed("events"), "events");
+        env::set_var(PREFIX_ENV, "");
+        assert_eq!(prefixed("events"), "events");
+        env::set_var(PREFIX_ENV, "space");
+        assert_eq!(prefixed("events"), "space:events");
+        assert_eq!(prefixed("calendar_feed:https://example.com"), "space:calendar_feed:https://example.com");
+        env::remove_var(PREFIX_ENV);
+    }
+}